@@ -54,6 +54,7 @@ Description=macrokey
 
 [Service]
 ExecStart=${DIR}/macrokey
+ExecReload=kill -HUP \$MAINPID
 Restart=on-failure
 #StartLimitIntervalSec=60
 #StartLimitBurst=4
@@ -76,6 +77,7 @@ Description=macrokey
 
 [Service]
 ExecStart=${DIR}/macrokey
+ExecReload=kill -HUP \$MAINPID
 Restart=on-failure
 
 [Install]
@@ -0,0 +1,95 @@
+use clap::{
+    Parser,
+    Subcommand,
+};
+use std::path::PathBuf;
+use crate::control::{
+    Inject,
+    Request,
+};
+
+/// A simple rust alternative to auto hotkey (AHK) for linux.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file, defaults to ~/.config/macrokey/config.toml
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Control socket of the running macrokey, defaults to $XDG_RUNTIME_DIR/macrokey.sock
+    #[arg(long, global = true)]
+    pub socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the tasks from the config, the default when no command is given.
+    Run,
+    /// List all input devices.
+    ListDevices {
+        /// Print the devices as json.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Log the events of all devices with a name matching the regex.
+    Monitor {
+        /// Regex to match device names, "" for all devices.
+        #[arg(default_value = "")]
+        regex: String,
+        /// Log releases and repeats, not just key presses.
+        #[arg(long)]
+        all_values: bool,
+    },
+    /// Check the config file for errors and exit, a missing file is an error.
+    CheckConfig,
+    /// Record a device in the evemu format until Ctrl+C, for `macrokey replay` or `evemu-play`.
+    Record {
+        /// Regex of the device name, a table of device fields as in the config such as
+        /// `{ vendor = 0x046d, product = 0xc52b }`, or a /dev/input/event* path.
+        #[arg(default_value = "")]
+        device: String,
+        /// Vendor id of the device in hex, as listed by `list-devices`.
+        #[arg(long, value_parser = parse_id)]
+        vendor: Option<u16>,
+        /// Product id of the device in hex, as listed by `list-devices`.
+        #[arg(long, value_parser = parse_id)]
+        product: Option<u16>,
+        /// Physical path of the device, e.g. `usb-0000:00:14.0-1/input0`.
+        #[arg(long)]
+        phys: Option<String>,
+        /// File to write, stdout if not given.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Feed a recorded device through the tasks of the config and print what they would send.
+    Replay {
+        /// File in the evemu format, from `macrokey record` or `evemu-record`.
+        trace: PathBuf,
+        /// Only this task, by default every task of the config reading the recorded device.
+        #[arg(long)]
+        task: Option<String>,
+        /// Compare what is sent to the events of this file instead of printing it.
+        #[arg(long)]
+        expect: Option<PathBuf>,
+        /// Ms the tasks keep running after the last event, for their timers.
+        #[arg(long, default_value_t = 1000)]
+        linger: u64,
+    },
+    /// Query or drive the running macrokey through its control socket.
+    Ctl {
+        #[command(subcommand)]
+        request: Request,
+    },
+    /// Send events through the virtual device of the running macrokey.
+    #[command(flatten)]
+    Inject(Inject),
+}
+
+
+/// A vendor or product id in hex, with or without `0x`.
+fn parse_id(id: &str) -> Result<u16, String> {
+    u16::from_str_radix(id.trim_start_matches("0x"), 16).map_err(|e| format!("`{}` is not a hex id: {}", id, e))
+}
@@ -4,30 +4,135 @@ extern crate log;
 #[macro_use]
 mod macros;
 
-use tokio::{
-    self,
-    task::JoinSet,
+use clap::Parser;
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::Duration,
 };
 
+mod cli;
 mod utils;
 mod tasks;
+use cli::{
+    Cli,
+    Command,
+};
 use tasks::*;
 use utils::*;
+use device_matcher::DeviceMatcher;
 
 
 #[tokio::main]
 async fn main() {
     functions::init_logger();
+    let cli = Cli::parse();
+    let config_path = cli.config.unwrap_or_else(config::default_path);
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(config_path).await,
+        Command::ListDevices { json } => {
+            functions::check_permissions();
+            match json {
+                false => functions::list_devices(),
+                true => functions::print_devices_json(),
+            }
+        }
+        Command::Monitor { regex, all_values } => {
+            functions::check_permissions();
+            match DeviceMatcher::name_regex(&regex) {
+                // include the virtual device, to see what the tasks send
+                Ok(device) => if let Err(e) = monitor::task(monitor::Config { device: DeviceMatcher { exclude_virtual: false, ..device }, all_values }).await {
+                    error!("{}", e);
+                },
+                Err(e) => error!("{}", e),
+            }
+        }
+        Command::CheckConfig => match config::read(&config_path) {
+            Ok(config) => info!("{}: ok, {} tasks", config_path.display(), config.tasks.len()),
+            Err(e) => {
+                error!("Config error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Command::Record { device, vendor, product, phys, output } => record(device, vendor, product, phys, output).await,
+        Command::Replay { trace, task, expect, linger } => replay_trace(config_path, trace, task, expect, linger).await,
+        Command::Ctl { request } => ctl(cli.socket, request).await,
+        Command::Inject(inject) => ctl(cli.socket, control::Request::Inject(inject)).await,
+    }
+}
+
+
+/// Send a request to the running macrokey and print the answer.
+async fn ctl(socket: Option<PathBuf>, request: control::Request) {
+    match control::send(&socket.unwrap_or_else(control::socket_path), &request).await {
+        Ok(data) if data.is_null() => {}
+        Ok(data) => println!("{}", serde_json::to_string_pretty(&data).unwrap_or_default()),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+
+/// Record the device at a /dev path, or the one matching the device and the ids, see [`evemu::record`].
+async fn record(device: String, vendor: Option<u16>, product: Option<u16>, phys: Option<String>, output: Option<PathBuf>) {
+    let opened = if device.starts_with("/dev/") {
+        evemu::open(Path::new(&device))
+    } else {
+        device.parse::<DeviceMatcher>().and_then(|matcher| evemu::find(&DeviceMatcher {
+            vendor: vendor.or(matcher.vendor),
+            product: product.or(matcher.product),
+            phys: phys.or(matcher.phys),
+            ..matcher
+        }))
+    };
+    let recorded = match opened {
+        Ok(device) => evemu::record(device, output.as_deref()).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = recorded {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+
+/// Replay a trace through the tasks of the config, see [`replay::replay`].
+async fn replay_trace(config_path: PathBuf, trace: PathBuf, task: Option<String>, expect: Option<PathBuf>, linger: u64) {
+    let replayed = match config::load(&config_path) {
+        // each task is replayed on a runtime of its own
+        Ok(config) => tokio::task::spawn_blocking(move || {
+            replay::replay(&config, &trace, task.as_deref(), Duration::from_millis(linger), expect.as_deref())
+        }).await.unwrap_or_else(|e| Err(e.to_string())),
+        Err(e) => Err(format!("Config error: {}", e)),
+    };
+    if let Err(e) = replayed {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+
+async fn run(config_path: PathBuf) {
     info!("== Start MacroKey ==");
     functions::check_permissions();
     functions::list_devices();
+
+    let config = match config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Config error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     info!("\n== Start Tasks ==");
-    // tasks
-    let mut set = JoinSet::new();
-    //set.spawn(monitor::task("")); // log all devices/events
-    //set.spawn(hotkeys::task());
-    //set.spawn(auto_repeat::task());
-    set.spawn(remote::task());
-    set.spawn(virtual_device::task());
-    set.join_all().await;
-}
\ No newline at end of file
+    config::apply(&config).await;
+    tokio::spawn(config::watch(config_path, config));
+    tokio::spawn(control::serve(control::socket_path()));
+    shutdown::coordinate(tokio::spawn(virtual_device::task())).await
+}
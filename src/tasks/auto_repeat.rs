@@ -1,108 +1,409 @@
 use evdev::{
-    Device, 
+    Device,
     EventType,
-    EventSummary,
-    InputEvent, 
+    InputEvent,
     KeyCode,
     LedCode,
 };
 use tokio::{
     sync::Mutex,
-    task::{
-        JoinSet,
-        JoinHandle,
-    },
+    task::JoinHandle,
     time::{
         sleep,
         Duration
     }
 };
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::HashMap,
+    num::NonZeroU32,
+    str::FromStr,
+    sync::Arc,
+};
 use crate::{
-    functions, 
-    key_event_type::KeyEventType, 
-    signals
+    chord::{
+        self,
+        Key,
+        Modifiers,
+    },
+    device_matcher::DeviceMatcher,
+    devices,
+    error::{
+        self,
+        Error,
+    },
+    functions,
+    input::{
+        self,
+        InputSink,
+        InputSource,
+    },
+    key_event_type::KeyEventType,
+    replay::Player,
+    signals::{
+        self,
+        VirtualDeviceTx,
+    },
+    tasks::{
+        self,
+        Task,
+    },
 };
 
-const PRESSED_TIME: u64 = 100; // ms
-const RELEASED_TIME: u64 = 350; // ms
 const TASK_ID: &str = "AUTO REPEAT";
-static STATE: Lazy<Mutex<State>> = Lazy::new(|| Mutex::new(State::new()));
+
+/// The state shared by the keyboards of a task.
+type Shared = Arc<Mutex<State>>;
+
+/// The state of each running auto repeat task, by task name, for the control socket.
+static STATES: Lazy<std::sync::Mutex<HashMap<String, Shared>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// Repeatedly tap keys on the virtual device.
+///
+/// Hold the `start` modifiers and press a key to start repeating it, press the
+/// key again without the modifiers to stop it. The defaults are the original
+/// behavior: Ctrl+Alt to start, ~ (tilde) to stop all and the Caps Lock led to pause.
+///
+/// ## Example
+///
+/// ```toml
+/// [[task]]
+/// type = "auto_repeat"
+/// device = "keyboard"
+/// start = "ctrl+alt"         # modifiers to hold when pressing a key to repeat
+/// stop_all = ["GRAVE"]       # keys which stop all repeats, [] for none
+/// pause = { led = "CAPSL" }  # or { key = "PAUSE" } to toggle with a key, or "none"
+/// hold = 100                 # ms the key is held down
+/// interval = 350             # ms between a release and the next press
+/// jitter = 0                 # up to this many random ms added to hold and interval
+/// max_repeats = 10           # stop after this many presses, unlimited if not given
+///
+/// [task.key.SPACE]           # per key timing, anything not given is from above
+/// hold = 50
+/// interval = 1000
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The keyboards to watch.
+    pub device: DeviceMatcher,
+    pub start: Modifiers,
+    pub stop_all: Vec<Key>,
+    pub pause: Pause,
+    pub hold: u64,
+    pub interval: u64,
+    pub jitter: u64,
+    pub max_repeats: Option<NonZeroU32>,
+    #[serde(rename = "key")]
+    pub keys: HashMap<Key, KeyTiming>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            device: DeviceMatcher::name_regex("keyboard").unwrap(),
+            start: Modifiers { ctrl: true, alt: true, ..Default::default() },
+            stop_all: vec![Key(KeyCode::KEY_GRAVE)],
+            pause: Pause::Led(Led(LedCode::LED_CAPSL)),
+            hold: 100,
+            interval: 350,
+            jitter: 0,
+            max_repeats: None,
+            keys: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Without a start modifier every key press would start repeating.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.start.intersects(&Modifiers { alt: true, ctrl: true, shift: true, meta: true, capslock: false }) {
+            return Err("start needs at least one of alt, ctrl, shift or meta".to_string());
+        }
+        Ok(())
+    }
+
+    /// The timing of a key, its own values first, then those of the task.
+    fn timing(&self, key: KeyCode) -> Timing {
+        let own = self.keys.get(&Key(key)).cloned().unwrap_or_default();
+        Timing {
+            hold: own.hold.unwrap_or(self.hold),
+            interval: own.interval.unwrap_or(self.interval),
+            jitter: own.jitter.unwrap_or(self.jitter),
+            max_repeats: own.max_repeats.or(self.max_repeats),
+        }
+    }
+}
+
+
+/// Timing of a single key, overriding the values of the task.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyTiming {
+    pub hold: Option<u64>,
+    pub interval: Option<u64>,
+    pub jitter: Option<u64>,
+    pub max_repeats: Option<NonZeroU32>,
+}
+
+
+/// What pauses and resumes all repeats, without the start modifiers held.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Pause {
+    /// Paused while the led is on, e.g. `CAPSL`.
+    Led(Led),
+    /// Each press toggles pause.
+    Key(Key),
+    None,
+}
+
+
+/// A led name such as `CAPSL` or `LED_NUML`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Led(pub LedCode);
+
+impl TryFrom<String> for Led {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let upper = s.trim().to_uppercase();
+        LedCode::from_str(&upper)
+            .or_else(|_| LedCode::from_str(&format!("LED_{}", upper)))
+            .map(Led)
+            .map_err(|_| format!("unknown led `{}`", s))
+    }
+}
+
+
+#[derive(Clone, Copy, Debug)]
+struct Timing {
+    hold: u64,
+    interval: u64,
+    jitter: u64,
+    max_repeats: Option<NonZeroU32>,
+}
+
+impl Timing {
+    fn delay(&self, ms: u64) -> Duration {
+        Duration::from_millis(ms + fastrand::u64(0..=self.jitter))
+    }
+}
 
 
-pub async fn task() {
+pub async fn task(config: Config) -> error::Result<()> {
     info!("{}", TASK_ID);
+    // each task has its own repeats, shared by its keyboards
+    let name = tasks::TASK_NAME.try_with(String::clone).unwrap_or_default();
+    let tx = signals::get_virtual_device_tx(&format!("task {}", name)).await;
+    let state = Arc::new(Mutex::new(State::new(config.clone(), tx)));
+    STATES.lock().unwrap().insert(name.clone(), state.clone());
+    let _guard = StopGuard(name, state.clone());
+    devices::for_each(|device| {
+        let matched = config.device.matches(&device);
+        matched.then(|| monitor_events(device, state.clone()))
+    }).await
+}
+
+impl Task for Config {
+    fn type_name(&self) -> &'static str {
+        "auto_repeat"
+    }
+
+    fn devices(&self) -> Vec<&DeviceMatcher> {
+        vec![&self.device]
+    }
+
+    async fn run(self) -> error::Result<()> {
+        task(self).await
+    }
+
+    async fn replay(self, events: Player, tx: VirtualDeviceTx) -> error::Result<()> {
+        process_events(events, Arc::new(Mutex::new(State::new(self, tx)))).await;
+        Ok(())
+    }
+}
+
+
+/// Stops all repeat timers when the task is stopped, they are spawned separately so would keep running.
+struct StopGuard(String, Arc<Mutex<State>>);
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        let mut states = STATES.lock().unwrap();
+        // a restarted task may have registered its new state already
+        if states.get(&self.0).is_some_and(|state| Arc::ptr_eq(state, &self.1)) {
+            states.remove(&self.0);
+        }
+        let state = self.1.clone();
+        tokio::spawn(async move {
+            state.lock().await.stop_all_repeat_events();
+        });
+    }
+}
+
+
+/// A repeating key, as listed on the control socket.
+#[derive(Clone, Debug, Serialize)]
+pub struct Repeat {
+    pub task: String,
+    pub key: Key,
+    pub paused: bool,
+}
+
+
+/// The keys being repeated by all auto repeat tasks.
+pub async fn repeats() -> Vec<Repeat> {
+    let mut repeats = Vec::new();
+    for (task, state) in states(None).unwrap_or_default() {
+        let state = state.lock().await;
+        for (key, (handle, _)) in state.repeat_events.iter() {
+            // paused repeats are aborted, finished ones reached their maximum
+            if !state.paused && handle.is_finished() { continue }
+            repeats.push(Repeat { task: task.clone(), key: Key(*key), paused: state.paused });
+        }
+    }
+    repeats.sort_by(|a, b| (&a.task, a.key.0.code()).cmp(&(&b.task, b.key.0.code())));
+    repeats
+}
+
+
+/// What to do with the repeats of a task, from the control socket.
+#[derive(Clone, Copy, Debug)]
+pub enum Control {
+    Start(Key),
+    Stop(Key),
+    StopAll,
+    Pause,
+    Resume,
+}
 
-    loop {
-        let mut set = JoinSet::new();
-        for device in functions::get_devices_by_regex("keyboard") {
-            set.spawn(monitor_events(device));
+
+/// Start, stop, pause or resume repeats, as if done with the keyboard.
+///
+/// `task` is the name of the auto repeat task, it can be left out if only one is running.
+/// Pause and resume apply to all tasks when no task is given.
+///
+/// ## Errors
+///
+/// Returns an `Err` if the task is not running, or no task is given while more than one is.
+pub async fn control(task: Option<&str>, control: Control) -> Result<(), String> {
+    let states = states(task)?;
+    if matches!(control, Control::Start(_) | Control::Stop(_)) && states.len() > 1 {
+        return Err("more than one auto_repeat task is running, give the task".to_string());
+    }
+    for (name, state) in states {
+        let mut state = state.lock().await;
+        match control {
+            Control::Start(key) => {
+                info!("{} {} start {:?}", TASK_ID, name, key.0);
+                let press = InputEvent::new_now(EventType::KEY.0, key.0.code(), KeyEventType::PRESSED.into());
+                state.start_repeat_event(key.0, press);
+            }
+            Control::Stop(key) => state.stop_repeat_event(key.0),
+            Control::StopAll => state.stop_all_repeat_events(),
+            Control::Pause => state.set_paused(true),
+            Control::Resume => state.set_paused(false),
         }
-        set.join_all().await;
-        
-        info!("{} error, retry in 60s", TASK_ID);
-        sleep(Duration::from_secs(60)).await;
+    }
+    Ok(())
+}
+
+
+/// The states of the named task, or of all tasks.
+fn states(task: Option<&str>) -> Result<Vec<(String, Shared)>, String> {
+    let states = STATES.lock().unwrap();
+    match task {
+        Some(task) => match states.get(task) {
+            Some(state) => Ok(vec![(task.to_string(), state.clone())]),
+            None => Err(format!("no running auto_repeat task named `{}`", task)),
+        },
+        None if states.is_empty() => Err("no auto_repeat task is running".to_string()),
+        None => Ok(states.iter().map(|(name, state)| (name.clone(), state.clone())).collect()),
     }
 }
 
 
-async fn monitor_events(device: Device) {
+async fn monitor_events(device: Device, state: Arc<Mutex<State>>) -> error::Result<()> {
     functions::log_device_keys(&device);
-    let mut events = device.into_event_stream().unwrap();
-    while let Ok(ev) = events.next_event().await {
+    let name = device.name().unwrap_or("<unnamed>").to_string();
+    let events = device.into_event_stream().map_err(|e| Error::Device { name, source: e })?;
+    process_events(events, state).await;
+    Ok(())
+}
+
+
+/// Start and stop repeats by the keys of a source, until it ends.
+async fn process_events(mut events: impl InputSource, state: Arc<Mutex<State>>) {
+    while let Some(ev) = events.next_event().await {
         // filter unwanted events, reduce locks
         if ev.event_type() != EventType::KEY && ev.event_type() != EventType::LED { continue };
 
         // lock and process
-        let mut state = STATE.lock().await;
+        let mut state = state.lock().await;
         state.process_input(ev).await;
     }
 }
 
 
-pub async fn repeat_event(ie: InputEvent) {
-    // if we exit this task when the key is down, it remains down.
-    let tx = signals::get_virtual_device_tx().await;
+async fn repeat_event(ie: InputEvent, timing: Timing, tx: VirtualDeviceTx) {
+    if let Err(e) = repeat(ie, timing, tx).await {
+        error!("{} stopped repeating {:?}: {}", TASK_ID, KeyCode::new(ie.code()), e);
+    }
+}
+
+
+async fn repeat(ie: InputEvent, timing: Timing, mut tx: impl InputSink) -> error::Result<()> {
+    // a key left down when aborted is released by `stop_repeat_event`, or by the virtual device on shutdown
     let key_code = ie.code();
     let press = InputEvent::new_now(EventType::KEY.0, key_code, KeyEventType::PRESSED.into());
     let release = InputEvent::new_now(EventType::KEY.0, key_code, KeyEventType::RELEASED.into());
-    loop {
-        tx.send(press).await.unwrap();
-        sleep(Duration::from_millis(PRESSED_TIME)).await;
-        tx.send(release).await.unwrap();
-        sleep(Duration::from_millis(RELEASED_TIME)).await;
+    let mut count = 0;
+    while timing.max_repeats.is_none_or(|max| count < max.get()) {
+        count += 1;
+        tx.send(input::frame([press])).await?;
+        sleep(timing.delay(timing.hold)).await;
+        tx.send(input::frame([release])).await?;
+        sleep(timing.delay(timing.interval)).await;
     }
+    Ok(())
 }
 
 
-pub async fn stop_repeat_event(ie: InputEvent) {
+async fn stop_repeat_event(ie: InputEvent, mut tx: impl InputSink) {
     // ensure the key is released when exiting the task
     let key_code = ie.code();
-    let tx = signals::get_virtual_device_tx().await;
     let release = InputEvent::new_now(EventType::KEY.0, key_code, KeyEventType::RELEASED.into());
-    tx.send(release).await.unwrap();
+    if let Err(e) = tx.send(input::frame([release])).await {
+        error!("{} {}", TASK_ID, e);
+    }
 }
 
 
 
 #[derive(Debug)]
 struct State {
-    alt_pressed: bool,
-    ctrl_pressed: bool,
-    capslock_pressed: bool,
-    meta_pressed: bool,
+    config: Config,
+    modifiers: Modifiers,
+    paused: bool,
     repeat_events: HashMap<KeyCode, (JoinHandle<()>, InputEvent)>,
+    /// Where the repeated keys are sent, the virtual device.
+    tx: VirtualDeviceTx,
 }
 
 impl State {
-    fn new() -> Self {
+    fn new(config: Config, tx: VirtualDeviceTx) -> Self {
         State {
-            alt_pressed: false,
-            ctrl_pressed: false,
-            capslock_pressed: false,
-            meta_pressed: false,
+            config,
+            modifiers: Modifiers::default(),
+            paused: false,
             repeat_events: HashMap::new(),
+            tx,
         }
     }
 
@@ -111,7 +412,7 @@ impl State {
         // log
         //info!(" > {:?}", ev.destructure());
 
-        self.update_state(&ev);
+        self.modifiers.update(&ev);
 
         // timers start
         // all modifiers pressed + repeatable key + not already a repeat
@@ -122,8 +423,15 @@ impl State {
         // timers end
         // no modifiers pressed + repeatable key
         if !self.any_modifier_pressed() && self.is_not_modifier(ev) {
+            // toggle key
+            if self.is_toggle_pause(ev) {
+                self.paused = !self.paused;
+                self.apply_pause();
+                return;
+            }
+
             // active repeat event
-            if self.is_active_repeat_event(ev) { // todo: flakey, modifiers stop working when using these... why?
+            if self.repeat_events.contains_key(&KeyCode::new(ev.code())) { // todo: flakey, modifiers stop working when using these... why?
                 self.stop_repeat_event(KeyCode::new(ev.code()));
             }
 
@@ -133,54 +441,21 @@ impl State {
                 self.stop_all_repeat_events();
             }
         }
-        // no modifiers pressed + toggle key(led)
-        else if !self.any_modifier_pressed() && self.is_toggle_pause(ev) {
-            match self.is_toggle_pressed() {
-                true => self.pause_all_repeat_events(),
-                false => self.resume_all_repeat_events(),
-            }
+        // no modifiers pressed + toggle led, a pause key is only toggled by its press
+        else if !self.any_modifier_pressed() && ev.event_type() == EventType::LED && self.is_toggle_pause(ev) {
+            self.paused = ev.value() == 1;
+            self.apply_pause();
         }
     }
 
-
-    fn update_state(&mut self, ev: &InputEvent) {
-        match ev.destructure() {
-            EventSummary::Key(_, KeyCode::KEY_LEFTALT | KeyCode::KEY_RIGHTALT, value) => {
-                self.alt_pressed = value == KeyEventType::PRESSED || value == KeyEventType::REPEAT;
-            }
-            EventSummary::Key(_, KeyCode::KEY_LEFTCTRL | KeyCode::KEY_RIGHTCTRL, value) => {
-                self.ctrl_pressed = value == KeyEventType::PRESSED || value == KeyEventType::REPEAT;
-            }
-            EventSummary::Key(_, KeyCode::KEY_LEFTMETA | KeyCode::KEY_RIGHTMETA, value) => {
-                self.meta_pressed = value == KeyEventType::PRESSED || value == KeyEventType::REPEAT;
-            }
-            EventSummary::Led(_, LedCode::LED_CAPSL, value) => {
-                self.capslock_pressed = value == 1;
-            }
-            _ => {}
-        }
-    }
-
-    /// Returns true if all modifier keys are currently pressed.
-    /// 
-    /// The modifier keys are currently defined as:
-    /// 
-    /// - Ctrl
-    /// - Alt
+    /// Returns true if all of the start modifiers are currently pressed.
     fn all_modifiers_pressed(&mut self) -> bool {
-        // modifier keys here
-        self.ctrl_pressed && self.alt_pressed
+        self.modifiers.contains(&self.config.start)
     }
 
-    /// Returns true if any of the modifier keys are currently pressed.
-    ///
-    /// The modifier keys are currently defined as:
-    ///
-    /// - Ctrl
-    /// - Alt
+    /// Returns true if any of the start modifiers are currently pressed.
     fn any_modifier_pressed(&mut self) -> bool {
-        // modifier keys here
-        self.ctrl_pressed || self.alt_pressed
+        self.modifiers.intersects(&self.config.start)
     }
 
 
@@ -188,11 +463,6 @@ impl State {
     /// Returns true if the given event is a repeatable key press.
     ///
     /// A repeatable key press is one that is not a modifier key.
-    ///
-    /// The modifier keys currently checked are:
-    ///
-    /// - Ctrl
-    /// - Alt
     fn is_not_modifier(&mut self, ev: InputEvent) -> bool {
         // filter dud events
         if ev.event_type() == EventType::KEY && ev.value() == KeyEventType::PRESSED { } // pass
@@ -200,73 +470,207 @@ impl State {
 
         // modifier keys here
         // and special keys to ignore
-        match ev.destructure() {
-            EventSummary::Key(_, KeyCode::KEY_LEFTALT | KeyCode::KEY_RIGHTALT, _) => { return false } // alt key
-            EventSummary::Key(_, KeyCode::KEY_LEFTCTRL | KeyCode::KEY_RIGHTCTRL, _) => { return false } // ctl key
-            EventSummary::Key(_, KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT, _) => { return false } // shift
-            EventSummary::Key(_, KeyCode::KEY_LEFTMETA | KeyCode::KEY_RIGHTMETA, _) => { return false } // meta
-            EventSummary::Key(_, KeyCode::KEY_CAPSLOCK, _) => { return false } // caps
-            _ => {}
-        }
-
-        true
+        !chord::is_modifier(KeyCode::new(ev.code()))
     }
 
+    /// Returns true if the key is repeating, a repeat which reached its maximum can be started again.
+    ///
+    /// Paused repeats are aborted, but still held until they resume.
     fn is_active_repeat_event(&mut self, ev: InputEvent) -> bool {
         let key = KeyCode::new(ev.code());
-        self.repeat_events.contains_key(&key)
+        self.repeat_events.get(&key).is_some_and(|(handle, _)| self.paused || !handle.is_finished())
     }
 
     fn is_stop_all_key(&mut self, ev: InputEvent) -> bool {
         let key = KeyCode::new(ev.code());
-        key == KeyCode::KEY_GRAVE
+        self.config.stop_all.contains(&Key(key))
     }
 
     fn is_toggle_pause(&mut self, ev: InputEvent) -> bool {
-        // monitoring the led state, not the button itself
-        if ev.event_type() == EventType::LED && ev.code() == LedCode::LED_CAPSL.0 { return true }
-        false
+        match self.config.pause {
+            // monitoring the led state, not the button itself
+            Pause::Led(led) => ev.event_type() == EventType::LED && ev.code() == led.0.0,
+            Pause::Key(key) => ev.event_type() == EventType::KEY && ev.code() == key.0.code(),
+            Pause::None => false,
+        }
+    }
+
+    /// Pause or resume, doing nothing if already so.
+    fn set_paused(&mut self, paused: bool) {
+        if self.paused == paused { return }
+        self.paused = paused;
+        self.apply_pause();
     }
 
-    fn is_toggle_pressed(&mut self) -> bool {
-        self.capslock_pressed
+    fn apply_pause(&mut self) {
+        match self.paused {
+            true => self.pause_all_repeat_events(),
+            false => self.resume_all_repeat_events(),
+        }
     }
 
     fn stop_repeat_event(&mut self, key: KeyCode) {
         if let Some(value) = self.repeat_events.remove(&key) {
             value.0.abort();
-            tokio::spawn(stop_repeat_event(value.1));
+            tokio::spawn(stop_repeat_event(value.1, self.tx.clone()));
         }
     }
 
     fn start_repeat_event(&mut self, key: KeyCode, ie: InputEvent) {
-        let handle = tokio::spawn(repeat_event(ie));
+        // replaces a repeat which reached its maximum
+        if let Some((handle, _)) = self.repeat_events.get(&key) { handle.abort() }
+        let handle = tokio::spawn(repeat_event(ie, self.config.timing(key), self.tx.clone()));
         self.repeat_events.insert(key, (handle, ie));
     }
 
     fn stop_all_repeat_events(&mut self) {
         for value in self.repeat_events.values() {
             value.0.abort();
-            tokio::spawn(stop_repeat_event(value.1));
+            tokio::spawn(stop_repeat_event(value.1, self.tx.clone()));
         }
         self.repeat_events.clear();
     }
 
     fn pause_all_repeat_events(&mut self) {
+        // repeats which reached their maximum are done, so they are not resumed
+        self.repeat_events.retain(|_, (handle, _)| !handle.is_finished());
         for value in self.repeat_events.values() {
             value.0.abort();
-            tokio::spawn(stop_repeat_event(value.1));
+            tokio::spawn(stop_repeat_event(value.1, self.tx.clone()));
         }
     }
 
     fn resume_all_repeat_events(&mut self) {
         let mut new_events = Vec::new();
-        for value in self.repeat_events.values() {
-            let handle = tokio::spawn(repeat_event(value.1.clone()));
-            new_events.push((KeyCode::new(value.1.code()), (handle, value.1)));
+        for (key, value) in self.repeat_events.iter() {
+            let handle = tokio::spawn(repeat_event(value.1, self.config.timing(*key), self.tx.clone()));
+            new_events.push((*key, (handle, value.1)));
         }
         for (key, value) in new_events {
             self.repeat_events.insert(key, value);
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{
+        MemorySink,
+        MemorySource,
+    };
+
+    /// Feed the source to a task with the config, returns what it sent to the virtual device.
+    async fn run(toml: &str, source: MemorySource) -> MemorySink {
+        let config: Config = toml::from_str(toml).unwrap();
+        let (tx, sink) = MemorySink::channel();
+        process_events(source, Arc::new(Mutex::new(State::new(config, tx)))).await;
+        sink
+    }
+
+    /// Start repeating F5 with ctrl+alt.
+    fn start() -> MemorySource {
+        MemorySource::new()
+            .press(KeyCode::KEY_LEFTCTRL)
+            .press(KeyCode::KEY_LEFTALT)
+            .tap(KeyCode::KEY_F5)
+            .release(KeyCode::KEY_LEFTALT)
+            .release(KeyCode::KEY_LEFTCTRL)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeats_until_pressed_again() {
+        let source = start().wait(800).press(KeyCode::KEY_F5).wait(1000);
+        let sink = run("", source).await;
+        assert_eq!(sink.timed_keys(), [
+            (0, KeyCode::KEY_F5, 1),
+            (100, KeyCode::KEY_F5, 0),
+            (450, KeyCode::KEY_F5, 1),
+            (550, KeyCode::KEY_F5, 0),
+            (800, KeyCode::KEY_F5, 0),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn key_timing_and_max_repeats() {
+        let toml = r#"
+            max_repeats = 2
+            key.F5 = { hold = 50, interval = 200 }
+        "#;
+        let sink = run(toml, start().wait(2000)).await;
+        assert_eq!(sink.timed_keys(), [
+            (0, KeyCode::KEY_F5, 1),
+            (50, KeyCode::KEY_F5, 0),
+            (250, KeyCode::KEY_F5, 1),
+            (300, KeyCode::KEY_F5, 0),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stop_all_key() {
+        let source = start().wait(50).tap(KeyCode::KEY_GRAVE).wait(1000);
+        let sink = run("", source).await;
+        assert_eq!(sink.timed_keys(), [(0, KeyCode::KEY_F5, 1), (50, KeyCode::KEY_F5, 0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_key_toggles() {
+        let source = start()
+            .wait(200)
+            .tap(KeyCode::KEY_PAUSE)
+            .wait(1000)
+            .tap(KeyCode::KEY_PAUSE)
+            .wait(150);
+        let sink = run(r#"pause = { key = "PAUSE" }"#, source).await;
+        assert_eq!(sink.timed_keys(), [
+            (0, KeyCode::KEY_F5, 1),
+            (100, KeyCode::KEY_F5, 0),
+            (200, KeyCode::KEY_F5, 0),
+            (1200, KeyCode::KEY_F5, 1),
+            (1300, KeyCode::KEY_F5, 0),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn start_paused() {
+        let source = start()
+            .wait(200)
+            .tap(KeyCode::KEY_PAUSE)
+            .wait(100)
+            .press(KeyCode::KEY_LEFTCTRL)
+            .press(KeyCode::KEY_LEFTALT)
+            .tap(KeyCode::KEY_F5)
+            .release(KeyCode::KEY_LEFTALT)
+            .release(KeyCode::KEY_LEFTCTRL)
+            .wait(1000);
+        let sink = run(r#"pause = { key = "PAUSE" }"#, source).await;
+        // the paused repeat is not started again
+        assert_eq!(sink.timed_keys(), [
+            (0, KeyCode::KEY_F5, 1),
+            (100, KeyCode::KEY_F5, 0),
+            (200, KeyCode::KEY_F5, 0),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resume_does_not_restart_finished_repeats() {
+        let toml = r#"
+            max_repeats = 2
+            pause = { key = "PAUSE" }
+        "#;
+        let source = start()
+            .wait(1000)
+            .tap(KeyCode::KEY_PAUSE)
+            .wait(100)
+            .tap(KeyCode::KEY_PAUSE)
+            .wait(1000);
+        let sink = run(toml, source).await;
+        assert_eq!(sink.timed_keys(), [
+            (0, KeyCode::KEY_F5, 1),
+            (100, KeyCode::KEY_F5, 0),
+            (450, KeyCode::KEY_F5, 1),
+            (550, KeyCode::KEY_F5, 0),
+        ]);
+    }
+}
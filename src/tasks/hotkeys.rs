@@ -1,137 +1,503 @@
 use evdev::{
-    Device, 
     EventType,
-    EventSummary,
-    InputEvent, 
+    InputEvent,
     KeyCode,
-    LedCode,
 };
+use serde::Deserialize;
+use std::collections::HashSet;
 use tokio::{
     time::{
-        sleep,
-        Duration
-    }
+        Duration,
+        Instant,
+    },
 };
 use crate::{
-    functions, 
-    key_event_type::KeyEventType, 
+    action::Action,
+    device_matcher::DeviceMatcher,
+    devices,
+    error::{
+        self,
+        Error,
+    },
+    chord::{
+        self,
+        Chord,
+        Modifiers,
+    },
+    key_event_type::KeyEventType,
+    replay::Player,
+    signals::VirtualDeviceTx,
+    stage::{
+        self,
+        Pipeline,
+        Stage,
+    },
+    tasks::Task,
 };
 
 const TASK_ID: &str = "HOTKEYS";
 
+/// ## Example
+///
+/// ```toml
+/// [[task]]
+/// type = "hotkeys"
+/// device = { name = "AT Translated Set 2 keyboard" }
+/// leader = "ctrl+space"
+///
+/// [[task.binding]]
+/// chord = "ctrl+shift+z"
+/// action = { command = "wlr-which-key" }
+///
+/// [[task.binding]]
+/// sequence = "leader g s"   # chords pressed one after another
+/// timeout = 2000            # ms to wait for the next chord
+/// action = { command = "git status" }
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The keyboard to read hotkeys from.
+    pub device: DeviceMatcher,
+    /// Grab the keyboard and send its keys on, except the keys of bound chords.
+    /// The keys of a pending sequence are held back and replayed if it does not match.
+    pub grab: bool,
+    /// The chord written as `leader` in sequences.
+    pub leader: Option<Chord>,
+    /// Default ms to wait for the next chord of a sequence.
+    pub timeout: u64,
+    #[serde(rename = "binding")]
+    pub bindings: Vec<Binding>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            device: DeviceMatcher::name("AT Translated Set 2 keyboard"),
+            grab: false,
+            leader: None,
+            timeout: 1000,
+            bindings: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Each sequence can only be bound once, and can not be the start of another.
+    pub fn validate(&self) -> Result<(), String> {
+        let sequences = self.sequences()?;
+        for (i, sequence) in sequences.iter().enumerate() {
+            for other in sequences[..i].iter() {
+                if sequence.chords == other.chords {
+                    return Err(format!("`{}` is bound more than once", sequence.name));
+                }
+                if sequence.chords.starts_with(&other.chords) || other.chords.starts_with(&sequence.chords) {
+                    return Err(format!("`{}` and `{}` start the same, one would never run", other.name, sequence.name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The bindings as sequences of chords, a chord binding is a sequence of one.
+    fn sequences(&self) -> Result<Vec<Sequence>, String> {
+        self.bindings.iter().map(|binding| {
+            let (name, chords) = match (&binding.chord, &binding.sequence) {
+                (Some(chord), None) => (chord.to_string(), vec![*chord]),
+                (None, Some(sequence)) => (sequence.clone(), self.parse_sequence(sequence)?),
+                _ => return Err("a binding needs either a chord or a sequence".to_string()),
+            };
+            Ok(Sequence {
+                name,
+                chords,
+                timeout: Duration::from_millis(binding.timeout.unwrap_or(self.timeout)),
+                action: binding.action.clone(),
+            })
+        }).collect()
+    }
+
+    fn parse_sequence(&self, sequence: &str) -> Result<Vec<Chord>, String> {
+        let chords = sequence.split_whitespace().map(|part| match part {
+            "leader" => self.leader.ok_or_else(|| format!("`{}` uses leader but no leader is set", sequence)),
+            _ => part.parse(),
+        }).collect::<Result<Vec<Chord>, _>>()?;
+        if chords.is_empty() {
+            return Err("empty sequence".to_string());
+        }
+        Ok(chords)
+    }
+}
+
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Binding {
+    pub chord: Option<Chord>,
+    /// Whitespace separated chords, pressed one after another.
+    pub sequence: Option<String>,
+    /// Overrides the `timeout` of the task.
+    pub timeout: Option<u64>,
+    pub action: Action,
+}
+
+
+/// A binding with its chords parsed.
+#[derive(Clone, Debug)]
+struct Sequence {
+    name: String,
+    chords: Vec<Chord>,
+    timeout: Duration,
+    action: Action,
+}
+
+
 /// Bind apps to keys
-pub async fn task() {
+pub async fn task(config: Config) -> error::Result<()> {
     info!("{}", TASK_ID);
+    let sequences = config.sequences().map_err(Error::Config)?;
 
-    loop {
-        // predicate example
-        // get by name, and not a pointer(mouse)
-        // let device = try_return!(functions::get_device_by_predicate(|d| {
-        //     d.name().unwrap_or("") == "keyboard" && 
-        //     !functions::get_combined_properties(d).contains(&"POINTER".to_string())
-        // }));
+    devices::for_each(|device| {
+        let matched = config.device.matches(&device);
+        // each device can have its own state
+        let state: Box<dyn Stage> = Box::new(State::new(sequences.clone()));
+        matched.then(|| stage::run(device, config.grab, Pipeline::new(vec![state])))
+    }).await
+}
 
-        let device = functions::get_device_by_name("AT Translated Set 2 keyboard").unwrap();
-        monitor_events(device).await;
+impl Task for Config {
+    fn type_name(&self) -> &'static str {
+        "hotkeys"
+    }
 
-        info!("{} error, retry in 60s", TASK_ID);
-        sleep(Duration::from_secs(60)).await;
+    fn devices(&self) -> Vec<&DeviceMatcher> {
+        vec![&self.device]
     }
-}
 
+    async fn run(self) -> error::Result<()> {
+        task(self).await
+    }
 
-async fn monitor_events(device: Device) {
-    functions::log_device_keys(&device);
-    let mut events = device.into_event_stream().unwrap();
-    // each device can have its own state
-    let mut state = State::new();
-    while let Ok(ev) = events.next_event().await {
-        // filter unwanted events, reduce locks
-        if ev.event_type() != EventType::KEY && ev.event_type() != EventType::LED { continue };
-        state.process_input(ev).await;
+    async fn replay(self, events: Player, tx: VirtualDeviceTx) -> error::Result<()> {
+        let state = Box::new(State::new(self.sequences().map_err(Error::Config)?));
+        stage::forward(events, tx, self.grab, Pipeline::new(vec![state])).await
     }
 }
 
 
+/// The bindings as a stage of a pipeline, the keys of bound chords are not passed on.
+///
+/// ## Errors
+///
+/// Returns an `Err` if a sequence can not be parsed.
+pub fn stage(config: &Config) -> Result<Box<dyn Stage>, String> {
+    Ok(Box::new(State::new(config.sequences()?)))
+}
+
+
 #[derive(Debug)]
 struct State {
-    alt_pressed: bool,
-    ctrl_pressed: bool,
-    shift_pressed: bool,
-    capslock_pressed: bool,
-    meta_pressed: bool,
+    modifiers: Modifiers,
+    sequences: Vec<Sequence>,
+    /// The chords of a sequence pressed so far.
+    progress: Vec<Chord>,
+    /// When the pending sequence times out.
+    deadline: Option<Instant>,
+    /// Events held back while a sequence is pending.
+    held_back: Vec<InputEvent>,
+    /// Keys of chords in a sequence, their repeats and releases are held back as well.
+    held_keys: HashSet<KeyCode>,
+    /// Modifier keys held on the keyboard.
+    modifiers_down: HashSet<KeyCode>,
+    /// Modifier keys applications see as held, released when a chord is used.
+    modifiers_sent: HashSet<KeyCode>,
+    /// Actions of sequences matched, run once the modifiers released for them are sent.
+    actions: Vec<Action>,
+}
+
+impl Stage for State {
+    fn process(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        // filter unwanted events
+        if ev.event_type() != EventType::KEY && ev.event_type() != EventType::LED {
+            return vec![ev];
+        }
+        self.process_input(ev)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    // waited too long for the next chord of a sequence
+    fn timeout(&mut self) -> Vec<InputEvent> {
+        State::timeout(self)
+    }
+
+    fn actions(&mut self) -> Vec<Action> {
+        std::mem::take(&mut self.actions)
+    }
 }
 
 impl State {
-    fn new() -> Self {
+    fn new(sequences: Vec<Sequence>) -> Self {
         State {
-            alt_pressed: false,
-            ctrl_pressed: false,
-            shift_pressed: false,
-            capslock_pressed: false,
-            meta_pressed: false,
+            modifiers: Modifiers::default(),
+            sequences,
+            progress: Vec::new(),
+            deadline: None,
+            held_back: Vec::new(),
+            held_keys: HashSet::new(),
+            modifiers_down: HashSet::new(),
+            modifiers_sent: HashSet::new(),
+            actions: Vec::new(),
         }
     }
 
-    async fn process_input(&mut self, ev: InputEvent) -> () {
+    /// Returns the events to send on when the keyboard is grabbed.
+    ///
+    /// The keys of used chords are not sent. Their modifiers are released with
+    /// the chord, before its action runs, so applications never see ctrl or shift
+    /// held while the action sends keys, and pressed again before the next key that is sent.
+    fn process_input(&mut self, ev: InputEvent) -> Vec<InputEvent> {
         // log
         //info!(" > {:?}", ev.destructure());
-        self.update_state(&ev);
-
-        // set hotkey actions here
-        // all modifiers pressed + hotkey
-        if self.all_modifiers_pressed() && self.is_not_modifier(ev) {
-            match ev.destructure() {
-                EventSummary::Key(_, KeyCode::KEY_Z, _) => {
-                    let _ = functions::run_command("wlr-which-key").await;
-                }
-                _ => {}
-            }
+        self.modifiers.update(&ev);
+
+        let key = KeyCode::new(ev.code());
+        // caps lock toggles on press, so it is never pressed again
+        if ev.event_type() != EventType::KEY || key == KeyCode::KEY_CAPSLOCK {
+            return vec![ev];
+        }
+        if chord::is_modifier(key) {
+            return self.modifier(ev);
         }
-    }
 
+        if ev.value() != KeyEventType::PRESSED {
+            if !self.held_keys.contains(&key) { return vec![ev] }
+            if ev.value() == KeyEventType::RELEASED { self.held_keys.remove(&key); }
+            if !self.progress.is_empty() { self.held_back.push(ev) }
+            return Vec::new();
+        }
 
-    fn update_state(&mut self, ev: &InputEvent) {
-        match ev.destructure() {
-            EventSummary::Key(_, KeyCode::KEY_LEFTALT | KeyCode::KEY_RIGHTALT, value) => {
-                self.alt_pressed = value == KeyEventType::PRESSED || value == KeyEventType::REPEAT;
+        let mut forward = Vec::new();
+        if !self.step(ev) && !self.progress.is_empty() {
+            // not the next chord of any sequence, replay and try it as the start of one
+            forward = self.cancel();
+            if !self.step(ev) {
+                forward.push(ev);
+                return forward;
             }
-            EventSummary::Key(_, KeyCode::KEY_LEFTCTRL | KeyCode::KEY_RIGHTCTRL, value) => {
-                self.ctrl_pressed = value == KeyEventType::PRESSED || value == KeyEventType::REPEAT;
-            }
-            EventSummary::Key(_, KeyCode::KEY_LEFTMETA | KeyCode::KEY_RIGHTMETA, value) => {
-                self.meta_pressed = value == KeyEventType::PRESSED || value == KeyEventType::REPEAT;
-            }
-            EventSummary::Key(_, KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT, value) => {
-                self.shift_pressed = value == KeyEventType::PRESSED || value == KeyEventType::REPEAT;
+        } else if self.progress.is_empty() && !self.held_keys.contains(&key) {
+            forward.extend(self.press_modifiers());
+            forward.push(ev);
+            return forward;
+        }
+        forward.extend(self.release_modifiers());
+        forward
+    }
+
+    /// Modifier keys are sent as they are, unless released for a chord.
+    fn modifier(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        let key = KeyCode::new(ev.code());
+        match KeyEventType::from(ev.value()) {
+            KeyEventType::PRESSED => {
+                self.modifiers_down.insert(key);
+                self.modifiers_sent.insert(key);
+                vec![ev]
             }
-            EventSummary::Led(_, LedCode::LED_CAPSL, value) => {
-                self.capslock_pressed = value == 1;
+            KeyEventType::RELEASED => {
+                self.modifiers_down.remove(&key);
+                if self.modifiers_sent.remove(&key) { vec![ev] } else { Vec::new() }
             }
-            _ => {}
+            _ => if self.modifiers_sent.contains(&key) { vec![ev] } else { Vec::new() },
         }
     }
 
-    fn all_modifiers_pressed(&mut self) -> bool {
-        // set modifier keys here
-        self.ctrl_pressed && self.shift_pressed
+    /// Release the modifiers applications see, before a chord's action runs.
+    fn release_modifiers(&mut self) -> Vec<InputEvent> {
+        self.modifiers_sent.drain()
+            .map(|key| InputEvent::new_now(EventType::KEY.0, key.code(), KeyEventType::RELEASED.into()))
+            .collect()
     }
 
-    fn is_not_modifier(&mut self, ev: InputEvent) -> bool {
-        // filter dud events
-        if ev.event_type() == EventType::KEY && ev.value() == KeyEventType::PRESSED { } // pass
-        else { return false };
+    /// Press the modifiers which are held but were released for a chord.
+    fn press_modifiers(&mut self) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        for key in self.modifiers_down.iter() {
+            if self.modifiers_sent.insert(*key) {
+                events.push(InputEvent::new_now(EventType::KEY.0, key.code(), KeyEventType::PRESSED.into()));
+            }
+        }
+        events
+    }
 
-        // modifier keys here and special keys to ignore
-        match ev.destructure() {
-            EventSummary::Key(_, KeyCode::KEY_LEFTALT | KeyCode::KEY_RIGHTALT, _) => { return false } // alt key
-            EventSummary::Key(_, KeyCode::KEY_LEFTCTRL | KeyCode::KEY_RIGHTCTRL, _) => { return false } // ctl key
-            EventSummary::Key(_, KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT, _) => { return false } // shift
-            EventSummary::Key(_, KeyCode::KEY_LEFTMETA | KeyCode::KEY_RIGHTMETA, _) => { return false } // meta
-            EventSummary::Key(_, KeyCode::KEY_CAPSLOCK, _) => { return false } // caps
-            _ => {}
+    /// Advance the pending sequence with a key press, returns false if no sequence continues with it.
+    fn step(&mut self, ev: InputEvent) -> bool {
+        let next = self.progress.len();
+        let mut matching = self.sequences.iter().filter(|sequence| {
+            sequence.chords.len() > next
+                && sequence.chords[..next] == self.progress[..]
+                && sequence.chords[next].is_pressed(&self.modifiers, &ev)
+        }).peekable();
+        let Some(first) = matching.peek().copied() else { return false };
+        self.held_keys.insert(KeyCode::new(ev.code()));
+
+        // sequences can not start with another, so a complete match is the only match
+        if first.chords.len() == next + 1 {
+            info!("{} {}", TASK_ID, first.name);
+            self.actions.push(first.action.clone());
+            self.reset();
+            return true;
         }
 
+        let timeout = matching.map(|sequence| sequence.timeout).max().unwrap_or_default();
+        self.progress.push(first.chords[next]);
+        self.deadline = Some(Instant::now() + timeout);
+        self.held_back.push(ev);
+        let pending: Vec<String> = self.progress.iter().map(Chord::to_string).collect();
+        info!("{} pending {} ...", TASK_ID, pending.join(" "));
         true
     }
-}
\ No newline at end of file
+
+    /// The pending sequence timed out.
+    fn timeout(&mut self) -> Vec<InputEvent> {
+        info!("{} sequence timed out", TASK_ID);
+        self.cancel()
+    }
+
+    /// Give up on the pending sequence, returns the held back events to replay.
+    fn cancel(&mut self) -> Vec<InputEvent> {
+        let mut replay = self.press_modifiers();
+        replay.append(&mut self.held_back);
+        // the replayed presses are now sent, so their releases must be too
+        self.held_keys.clear();
+        self.reset();
+        replay
+    }
+
+    fn reset(&mut self) {
+        self.progress.clear();
+        self.deadline = None;
+        self.held_back.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use evdev::KeyCode;
+    use super::*;
+    use crate::memory::{
+        self,
+        MemorySource,
+    };
+
+    fn pipeline(toml: &str) -> Pipeline {
+        let config: Config = toml::from_str(toml).unwrap();
+        config.validate().unwrap();
+        Pipeline::new(vec![stage(&config).unwrap()])
+    }
+
+    const BINDINGS: &str = r#"
+        timeout = 500
+
+        [[binding]]
+        chord = "ctrl+z"
+        action = { keys = "x" }
+
+        [[binding]]
+        sequence = "a b"
+        action = { keys = "x" }
+    "#;
+
+    #[tokio::test]
+    async fn chord_is_not_passed_on() {
+        let source = MemorySource::new()
+            .press(KeyCode::KEY_LEFTCTRL)
+            .tap(KeyCode::KEY_Z)
+            .release(KeyCode::KEY_LEFTCTRL)
+            .tap(KeyCode::KEY_X);
+        let sink = memory::run(pipeline(BINDINGS), source).await;
+        assert_eq!(sink.keys(), [
+            (KeyCode::KEY_LEFTCTRL, 1),
+            // released before the action runs, and not again
+            (KeyCode::KEY_LEFTCTRL, 0),
+            (KeyCode::KEY_X, 1),
+            (KeyCode::KEY_X, 0),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn modifiers_are_pressed_again_after_a_chord() {
+        let source = MemorySource::new()
+            .press(KeyCode::KEY_LEFTCTRL)
+            .tap(KeyCode::KEY_Z)
+            .tap(KeyCode::KEY_C)
+            .release(KeyCode::KEY_LEFTCTRL);
+        let sink = memory::run(pipeline(BINDINGS), source).await;
+        assert_eq!(sink.keys(), [
+            (KeyCode::KEY_LEFTCTRL, 1),
+            (KeyCode::KEY_LEFTCTRL, 0),
+            (KeyCode::KEY_LEFTCTRL, 1),
+            (KeyCode::KEY_C, 1),
+            (KeyCode::KEY_C, 0),
+            (KeyCode::KEY_LEFTCTRL, 0),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn modifiers_are_released_before_the_action_runs() {
+        let source = MemorySource::new()
+            .press(KeyCode::KEY_LEFTCTRL)
+            .tap(KeyCode::KEY_Z)
+            .release(KeyCode::KEY_LEFTCTRL);
+        let sink = memory::run_actions(pipeline(BINDINGS), source).await;
+        assert_eq!(sink.keys(), [
+            (KeyCode::KEY_LEFTCTRL, 1),
+            (KeyCode::KEY_LEFTCTRL, 0),
+            // the keys of the action
+            (KeyCode::KEY_X, 1),
+            (KeyCode::KEY_X, 0),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sequence_is_not_passed_on() {
+        let source = MemorySource::new()
+            .tap(KeyCode::KEY_A)
+            .wait(300)
+            .tap(KeyCode::KEY_B)
+            .tap(KeyCode::KEY_C);
+        let sink = memory::run(pipeline(BINDINGS), source).await;
+        assert_eq!(sink.keys(), [(KeyCode::KEY_C, 1), (KeyCode::KEY_C, 0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_sequence_is_replayed() {
+        let source = MemorySource::new()
+            .tap(KeyCode::KEY_A)
+            .wait(1000)
+            .tap(KeyCode::KEY_B);
+        let sink = memory::run(pipeline(BINDINGS), source).await;
+        assert_eq!(sink.timed_keys(), [
+            (500, KeyCode::KEY_A, 1),
+            (500, KeyCode::KEY_A, 0),
+            (1000, KeyCode::KEY_B, 1),
+            (1000, KeyCode::KEY_B, 0),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_key_cancels_sequence() {
+        let source = MemorySource::new()
+            .tap(KeyCode::KEY_A)
+            .wait(100)
+            .tap(KeyCode::KEY_C);
+        let sink = memory::run(pipeline(BINDINGS), source).await;
+        assert_eq!(sink.timed_keys(), [
+            (100, KeyCode::KEY_A, 1),
+            (100, KeyCode::KEY_A, 0),
+            (100, KeyCode::KEY_C, 1),
+            (100, KeyCode::KEY_C, 0),
+        ]);
+    }
+}
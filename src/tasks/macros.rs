@@ -0,0 +1,509 @@
+use evdev::{
+    EventType,
+    InputEvent,
+    KeyCode,
+};
+use once_cell::sync::Lazy;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    env,
+    num::NonZeroU32,
+    path::PathBuf,
+    time::SystemTime,
+};
+use tokio::{
+    fs,
+    sync::Mutex,
+    time::{
+        sleep,
+        Duration,
+    },
+};
+use crate::{
+    action,
+    chord::{
+        Chord,
+        Key,
+        Modifiers,
+    },
+    device_matcher::DeviceMatcher,
+    devices,
+    error,
+    input,
+    key_event_type::KeyEventType,
+    signals::VirtualDeviceTx,
+    stage::{
+        self,
+        Pipeline,
+        Stage,
+    },
+    replay::Player,
+    tasks::Task,
+};
+
+const TASK_ID: &str = "MACROS";
+
+/// The macro being recorded, shared by all watched keyboards.
+static RECORDING: Lazy<std::sync::Mutex<Option<Recording>>> = Lazy::new(|| std::sync::Mutex::new(None));
+
+/// Macros recorded or loaded from disk, by name.
+static MACROS: Lazy<Mutex<HashMap<String, Macro>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Record macros from the keyboards, play them back with the `play_macro` action.
+///
+/// ## Example
+///
+/// ```toml
+/// [[task]]
+/// type = "macros"
+/// device = "keyboard"
+///
+/// [[task.record]]
+/// chord = "ctrl+alt+1"   # press to start recording, press again to stop and save
+/// name = "farm"
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The keyboards to record from.
+    pub device: DeviceMatcher,
+    pub record: Vec<Record>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            device: DeviceMatcher::name_regex("keyboard").unwrap(),
+            record: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Names must be usable as file names and each chord can only be bound once.
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, record) in self.record.iter().enumerate() {
+            check_name(&record.name)?;
+            if self.record[..i].iter().any(|r| r.chord == record.chord) {
+                return Err(format!("chord `{}` is bound more than once", record.chord));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// A chord that toggles recording of the named macro.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Record {
+    pub chord: Chord,
+    pub name: String,
+}
+
+
+/// How to play back a macro, used by the `play_macro` action.
+///
+/// ## Example
+///
+/// ```toml
+/// action = { play_macro = { name = "farm" } }                                  # as recorded
+/// action = { play_macro = { name = "farm", timing = { fixed = 50 } } }         # 50ms between events
+/// action = { play_macro = { name = "farm", timing = { speed = 2.0 }, repeat = 3 } }  # twice as fast, 3 times
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Playback {
+    pub name: String,
+    #[serde(default)]
+    pub timing: Timing,
+    #[serde(default = "once")]
+    pub repeat: NonZeroU32,
+}
+
+fn once() -> NonZeroU32 {
+    NonZeroU32::MIN
+}
+
+impl Playback {
+    pub fn validate(&self) -> Result<(), String> {
+        check_name(&self.name)?;
+        match self.timing {
+            Timing::Speed(speed) if !(speed.is_finite() && speed > 0.0) => {
+                Err(format!("macro `{}`: speed must be more than 0", self.name))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+
+/// The delay between the events of a macro.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Timing {
+    /// The delays as recorded.
+    #[default]
+    Original,
+    /// The same delay in ms between every event.
+    Fixed(u64),
+    /// The recorded delays divided by this.
+    Speed(f64),
+}
+
+
+/// A recorded macro, as saved to `<name>.toml` in the macro directory.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Macro {
+    #[serde(default, rename = "step")]
+    pub steps: Vec<Step>,
+}
+
+/// A key event of a macro.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub key: Key,
+    pub value: i32,
+    /// Time since the previous step in ms.
+    pub delay: u64,
+}
+
+
+/// A macro being recorded.
+struct Recording {
+    name: String,
+    steps: Vec<Step>,
+    last: Option<SystemTime>,
+    pressed: HashSet<KeyCode>,
+}
+
+impl Recording {
+    fn new(name: &str) -> Self {
+        Recording {
+            name: name.to_string(),
+            steps: Vec::new(),
+            last: None,
+            pressed: HashSet::new(),
+        }
+    }
+
+    fn record(&mut self, ev: &InputEvent) {
+        let key = KeyCode::new(ev.code());
+        match KeyEventType::from(ev.value()) {
+            KeyEventType::PRESSED => { self.pressed.insert(key); }
+            // skip keys that were already down when recording started
+            KeyEventType::RELEASED => if !self.pressed.remove(&key) { return },
+            // repeats are made by whoever receives the keys
+            _ => return,
+        }
+        let delay = self.last
+            .and_then(|last| ev.timestamp().duration_since(last).ok())
+            .unwrap_or_default();
+        self.last = Some(ev.timestamp());
+        self.steps.push(Step { key: Key(key), value: ev.value(), delay: delay.as_millis() as u64 });
+    }
+
+    /// Drop the presses that were never released, such as the chord that stopped the recording.
+    fn finish(self) -> Macro {
+        let mut held = self.pressed;
+        let mut steps: Vec<Step> = Vec::new();
+        for step in self.steps.into_iter().rev() {
+            if step.value == KeyEventType::PRESSED && held.remove(&step.key.0) {
+                // keep the timing of the steps after it
+                if let Some(next) = steps.last_mut() { next.delay += step.delay }
+                continue;
+            }
+            steps.push(step);
+        }
+        steps.reverse();
+        Macro { steps }
+    }
+}
+
+
+/// Where recorded macros are saved, `$XDG_DATA_HOME/macrokey/macros` or `~/.local/share/macrokey/macros`.
+pub fn directory() -> PathBuf {
+    let data_dir = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".local").join("share"),
+    };
+    data_dir.join("macrokey").join("macros")
+}
+
+
+/// Macro names are used as file names.
+pub fn check_name(name: &str) -> Result<(), String> {
+    if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        Ok(())
+    } else {
+        Err(format!("macro name `{}` can only have letters, numbers, `-` and `_`", name))
+    }
+}
+
+
+pub async fn task(config: Config) -> error::Result<()> {
+    info!("{}", TASK_ID);
+    devices::for_each(|device| {
+        let matched = config.device.matches(&device);
+        matched.then(|| stage::run(device, false, Pipeline::new(vec![stage(config.record.clone())])))
+    }).await
+}
+
+impl Task for Config {
+    fn type_name(&self) -> &'static str {
+        "macros"
+    }
+
+    fn devices(&self) -> Vec<&DeviceMatcher> {
+        vec![&self.device]
+    }
+
+    async fn run(self) -> error::Result<()> {
+        task(self).await
+    }
+
+    async fn replay(self, events: Player, tx: VirtualDeviceTx) -> error::Result<()> {
+        stage::forward(events, tx, false, Pipeline::new(vec![stage(self.record)])).await
+    }
+}
+
+
+/// Records the events passing through, all of them are passed on.
+pub fn stage(record: Vec<Record>) -> Box<dyn Stage> {
+    Box::new(Recorder { record, modifiers: Modifiers::default() })
+}
+
+
+/// Each device has its own modifiers, the recording is shared.
+struct Recorder {
+    record: Vec<Record>,
+    modifiers: Modifiers,
+}
+
+impl Stage for Recorder {
+    fn process(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        // filter unwanted events, reduce locks
+        if ev.event_type() != EventType::KEY && ev.event_type() != EventType::LED { return vec![ev] };
+        self.modifiers.update(&ev);
+
+        if let Some(record) = self.record.iter().find(|r| r.chord.is_pressed(&self.modifiers, &ev)) {
+            toggle_recording(&record.name);
+        } else if ev.event_type() == EventType::KEY {
+            if let Some(recording) = RECORDING.lock().unwrap().as_mut() {
+                recording.record(&ev);
+            }
+        }
+        vec![ev]
+    }
+}
+
+
+/// Stop and save the macro being recorded, then start recording `name` unless it was the one stopped.
+fn toggle_recording(name: &str) {
+    let mut recording = RECORDING.lock().unwrap();
+    if let Some(done) = recording.take() {
+        let stopped = done.name.clone();
+        match action::is_dry_run() {
+            true => info!("{} not saving {} (dry run)", TASK_ID, stopped),
+            false => { tokio::spawn(save(stopped.clone(), done.finish())); }
+        }
+        if stopped == name { return }
+    }
+    info!("{} recording {}", TASK_ID, name);
+    *recording = Some(Recording::new(name));
+}
+
+
+async fn save(name: String, recorded: Macro) {
+    info!("{} saved {}, {} events", TASK_ID, name, recorded.steps.len());
+    let path = directory().join(format!("{}.toml", name));
+    let written = async {
+        let text = toml::to_string(&recorded).map_err(|e| e.to_string())?;
+        fs::create_dir_all(directory()).await.map_err(|e| e.to_string())?;
+        fs::write(&path, text).await.map_err(|e| e.to_string())
+    };
+    if let Err(e) = written.await {
+        error!("{} can not save {}: {}", TASK_ID, path.display(), e);
+    }
+    MACROS.lock().await.insert(name, recorded);
+}
+
+
+/// Returns the named macro, loading it from disk the first time.
+async fn load(name: &str) -> Option<Macro> {
+    let mut macros = MACROS.lock().await;
+    if let Some(found) = macros.get(name) {
+        return Some(found.clone());
+    }
+    let path = directory().join(format!("{}.toml", name));
+    let loaded: Macro = match fs::read_to_string(&path).await.map(|text| toml::from_str(&text)) {
+        Ok(Ok(loaded)) => loaded,
+        Ok(Err(e)) => {
+            error!("{} {}: {}", TASK_ID, path.display(), e);
+            return None;
+        }
+        Err(e) => {
+            error!("{} no macro {}: {}", TASK_ID, name, e);
+            return None;
+        }
+    };
+    macros.insert(name.to_string(), loaded.clone());
+    Some(loaded)
+}
+
+
+/// Play a macro, sending its keys to `tx`.
+pub async fn play(playback: &Playback, tx: &VirtualDeviceTx) {
+    let Some(recorded) = load(&playback.name).await else { return };
+    let mut first = true;
+    for _ in 0..playback.repeat.get() {
+        for step in recorded.steps.iter() {
+            let delay = match playback.timing {
+                Timing::Original => Duration::from_millis(step.delay),
+                Timing::Fixed(delay) if !first => Duration::from_millis(delay),
+                Timing::Fixed(_) => Duration::ZERO,
+                Timing::Speed(speed) => Duration::from_millis(step.delay).div_f64(speed),
+            };
+            first = false;
+            if !delay.is_zero() { sleep(delay).await }
+            let ev = InputEvent::new_now(EventType::KEY.0, step.key.0.code(), step.value);
+            if tx.send(input::frame([ev])).await.is_err() { return }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use nix::libc;
+    use super::*;
+    use crate::memory::MemorySink;
+
+    /// A key event `ms` ms after the epoch, recordings take their delays from the event times.
+    fn key(key: KeyCode, value: i32, ms: u64) -> InputEvent {
+        InputEvent::from(libc::input_event {
+            time: libc::timeval { tv_sec: (ms / 1000) as _, tv_usec: (ms % 1000 * 1000) as _ },
+            type_: EventType::KEY.0,
+            code: key.code(),
+            value,
+        })
+    }
+
+    fn record(events: &[InputEvent]) -> Vec<(KeyCode, i32, u64)> {
+        let mut recording = Recording::new("test");
+        events.iter().for_each(|ev| recording.record(ev));
+        recording.finish().steps.iter().map(|step| (step.key.0, step.value, step.delay)).collect()
+    }
+
+    #[test]
+    fn stop_chord_is_dropped() {
+        let steps = record(&[
+            key(KeyCode::KEY_A, 1, 1000),
+            key(KeyCode::KEY_A, 0, 1100),
+            // ctrl+alt+1 stopped the recording, the 1 is not recorded
+            key(KeyCode::KEY_LEFTCTRL, 1, 1500),
+            key(KeyCode::KEY_LEFTALT, 1, 1600),
+        ]);
+        assert_eq!(steps, [(KeyCode::KEY_A, 1, 0), (KeyCode::KEY_A, 0, 100)]);
+    }
+
+    #[test]
+    fn delay_of_a_dropped_press_is_merged() {
+        let steps = record(&[
+            key(KeyCode::KEY_A, 1, 1000),
+            key(KeyCode::KEY_LEFTSHIFT, 1, 1100),
+            key(KeyCode::KEY_A, 0, 1250),
+        ]);
+        assert_eq!(steps, [(KeyCode::KEY_A, 1, 0), (KeyCode::KEY_A, 0, 250)]);
+    }
+
+    #[test]
+    fn keys_held_at_the_start_and_repeats_are_skipped() {
+        let steps = record(&[
+            key(KeyCode::KEY_B, 2, 1000),
+            key(KeyCode::KEY_B, 0, 1050),
+            key(KeyCode::KEY_A, 1, 1200),
+            key(KeyCode::KEY_A, 2, 1400),
+            key(KeyCode::KEY_A, 0, 1500),
+        ]);
+        assert_eq!(steps, [(KeyCode::KEY_A, 1, 0), (KeyCode::KEY_A, 0, 300)]);
+    }
+
+    /// Play a tap of A then a tap of B, returns the keys sent with their time.
+    async fn play_taps(playback: &str) -> Vec<(u64, KeyCode, i32)> {
+        let playback: Playback = toml::from_str(playback).unwrap();
+        let step = |key: KeyCode, value, delay| Step { key: Key(key), value, delay };
+        let taps = Macro { steps: vec![
+            step(KeyCode::KEY_A, 1, 0),
+            step(KeyCode::KEY_A, 0, 100),
+            step(KeyCode::KEY_B, 1, 50),
+            step(KeyCode::KEY_B, 0, 100),
+        ] };
+        // loaded as if recorded, each test has a name of its own
+        MACROS.lock().await.insert(playback.name.clone(), taps);
+        let sink = MemorySink::new();
+        let (tx, forwarding) = sink.forward();
+        play(&playback, &tx).await;
+        drop(tx);
+        forwarding.await.unwrap();
+        sink.timed_keys()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn original_timing() {
+        assert_eq!(play_taps(r#"name = "original""#).await, [
+            (0, KeyCode::KEY_A, 1),
+            (100, KeyCode::KEY_A, 0),
+            (150, KeyCode::KEY_B, 1),
+            (250, KeyCode::KEY_B, 0),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fixed_timing() {
+        assert_eq!(play_taps(r#"name = "fixed"
+            timing = { fixed = 20 }"#).await, [
+            (0, KeyCode::KEY_A, 1),
+            (20, KeyCode::KEY_A, 0),
+            (40, KeyCode::KEY_B, 1),
+            (60, KeyCode::KEY_B, 0),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn speed_timing() {
+        assert_eq!(play_taps(r#"name = "speed"
+            timing = { speed = 2.0 }"#).await, [
+            (0, KeyCode::KEY_A, 1),
+            (50, KeyCode::KEY_A, 0),
+            (75, KeyCode::KEY_B, 1),
+            (125, KeyCode::KEY_B, 0),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeat_plays_again() {
+        assert_eq!(play_taps(r#"name = "repeat"
+            timing = { fixed = 20 }
+            repeat = 2"#).await, [
+            (0, KeyCode::KEY_A, 1),
+            (20, KeyCode::KEY_A, 0),
+            (40, KeyCode::KEY_B, 1),
+            (60, KeyCode::KEY_B, 0),
+            // the first step of the next time round waits as well
+            (80, KeyCode::KEY_A, 1),
+            (100, KeyCode::KEY_A, 0),
+            (120, KeyCode::KEY_B, 1),
+            (140, KeyCode::KEY_B, 0),
+        ]);
+    }
+}
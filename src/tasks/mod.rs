@@ -1,5 +1,429 @@
 pub mod auto_repeat;
-pub mod remote;
+pub mod remap;
 pub mod monitor;
 pub mod hotkeys;
-pub mod virtual_device;
\ No newline at end of file
+pub mod macros;
+pub mod pipeline;
+pub mod virtual_device;
+
+use futures_util::FutureExt;
+use once_cell::sync::Lazy;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    panic::AssertUnwindSafe,
+};
+use tokio::{
+    sync::Mutex,
+    task::JoinHandle,
+    time::{
+        sleep,
+        Duration,
+        Instant,
+    },
+};
+use crate::{
+    action::Action,
+    device_matcher::DeviceMatcher,
+    error,
+    functions,
+    replay::Player,
+    signals::VirtualDeviceTx,
+    shutdown,
+};
+
+/// The first wait before a failed task is restarted, doubled on each failure.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest wait before a failed task is restarted. A task which ran this long
+/// before failing is restarted after [`MIN_BACKOFF`] again.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A task's config and its handle while running.
+type Running = (TaskConfig, Option<JoinHandle<()>>);
+
+/// Running tasks by name, a stopped task keeps its entry with no handle so it can be started again.
+static TASKS: Lazy<Mutex<HashMap<String, Running>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The tasks of the config and the active profile.
+static CURRENT: Lazy<Mutex<(Vec<TaskEntry>, Option<String>)>> = Lazy::new(|| Mutex::new((Vec::new(), None)));
+
+/// The health of each task, by name, kept by its supervisor.
+static HEALTH: Lazy<std::sync::Mutex<HashMap<String, Health>>> = Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+tokio::task_local! {
+    /// The name of the task being run, for the tasks to register what they are doing.
+    pub static TASK_NAME: String;
+}
+
+
+/// A task which can be run from the config.
+pub trait Task {
+    /// The `type` key of the task in the config file.
+    fn type_name(&self) -> &'static str;
+
+    /// The devices the task reads from, it waits for them to be plugged in.
+    fn devices(&self) -> Vec<&DeviceMatcher>;
+
+    /// Run the task, it only returns when it is done or failed.
+    fn run(self) -> impl Future<Output = error::Result<()>> + Send;
+
+    /// Feed a recorded device to the task instead of the devices it reads, until
+    /// the recording ends. What it would send to the virtual device goes to `tx`.
+    fn replay(self, events: Player, tx: VirtualDeviceTx) -> impl Future<Output = error::Result<()>> + Send;
+}
+
+/// A task entry in the config file, selected by its `type` key.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TaskConfig {
+    Monitor(monitor::Config),
+    Hotkeys(hotkeys::Config),
+    AutoRepeat(auto_repeat::Config),
+    Remap(remap::Config),
+    Macros(macros::Config),
+    Pipeline(pipeline::Config),
+}
+
+impl Task for TaskConfig {
+    fn type_name(&self) -> &'static str {
+        match self {
+            TaskConfig::Monitor(config) => config.type_name(),
+            TaskConfig::Hotkeys(config) => config.type_name(),
+            TaskConfig::AutoRepeat(config) => config.type_name(),
+            TaskConfig::Remap(config) => config.type_name(),
+            TaskConfig::Macros(config) => config.type_name(),
+            TaskConfig::Pipeline(config) => config.type_name(),
+        }
+    }
+
+    fn devices(&self) -> Vec<&DeviceMatcher> {
+        match self {
+            TaskConfig::Monitor(config) => config.devices(),
+            TaskConfig::Hotkeys(config) => config.devices(),
+            TaskConfig::AutoRepeat(config) => config.devices(),
+            TaskConfig::Remap(config) => config.devices(),
+            TaskConfig::Macros(config) => config.devices(),
+            TaskConfig::Pipeline(config) => config.devices(),
+        }
+    }
+
+    async fn run(self) -> error::Result<()> {
+        match self {
+            TaskConfig::Monitor(config) => config.run().await,
+            TaskConfig::Hotkeys(config) => config.run().await,
+            TaskConfig::AutoRepeat(config) => config.run().await,
+            TaskConfig::Remap(config) => config.run().await,
+            TaskConfig::Macros(config) => config.run().await,
+            TaskConfig::Pipeline(config) => config.run().await,
+        }
+    }
+
+    async fn replay(self, events: Player, tx: VirtualDeviceTx) -> error::Result<()> {
+        match self {
+            TaskConfig::Monitor(config) => config.replay(events, tx).await,
+            TaskConfig::Hotkeys(config) => config.replay(events, tx).await,
+            TaskConfig::AutoRepeat(config) => config.replay(events, tx).await,
+            TaskConfig::Remap(config) => config.replay(events, tx).await,
+            TaskConfig::Macros(config) => config.replay(events, tx).await,
+            TaskConfig::Pipeline(config) => config.replay(events, tx).await,
+        }
+    }
+}
+
+impl TaskConfig {
+    /// Checks the task config for errors serde can not catch.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            TaskConfig::Hotkeys(config) => config.validate(),
+            TaskConfig::Macros(config) => config.validate(),
+            TaskConfig::AutoRepeat(config) => config.validate(),
+            TaskConfig::Remap(config) => config.validate(),
+            TaskConfig::Pipeline(config) => config.validate(),
+            _ => Ok(()),
+        }
+    }
+
+    /// The actions bound by this task.
+    pub fn actions(&self) -> Vec<&Action> {
+        match self {
+            TaskConfig::Hotkeys(config) => config.bindings.iter().map(|b| &b.action).collect(),
+            TaskConfig::Remap(config) => config.actions(),
+            TaskConfig::Pipeline(config) => config.actions(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+
+/// A task with an optional `name`, used to refer to it from other tasks.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TaskEntry {
+    #[serde(default)]
+    name: Option<String>,
+    /// Profiles the task runs in, every profile if none are given.
+    #[serde(default)]
+    pub profiles: Vec<String>,
+    #[serde(flatten)]
+    pub task: TaskConfig,
+}
+
+impl TaskEntry {
+    /// The given name, or the type name if none was given.
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.task.type_name())
+    }
+
+    /// Returns true if the task runs in the profile.
+    pub fn in_profile(&self, profile: Option<&str>) -> bool {
+        self.profiles.is_empty() || profile.is_some_and(|profile| self.profiles.iter().any(|p| p == profile))
+    }
+}
+
+
+/// A task of the config, as listed on the control socket.
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskInfo {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_name: &'static str,
+    pub profiles: Vec<String>,
+    pub running: bool,
+    /// How the task has been doing since it was last started, if it was.
+    pub health: Option<Health>,
+}
+
+
+/// How a task has been doing, as kept by its supervisor.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Health {
+    pub status: Status,
+    /// Times the task was restarted after failing.
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    #[default]
+    Running,
+    /// Failed, waiting to be restarted.
+    Restarting,
+    /// Returned without an error, it is not restarted.
+    Finished,
+    /// Failed with an error a restart can not fix, macrokey shuts down.
+    Failed,
+    /// Stopped by a toggle or a config change.
+    Stopped,
+}
+
+
+/// Make the running tasks match the given tasks, for the active profile.
+///
+/// Tasks which were removed or whose config changed are stopped, new and changed
+/// tasks are started, and unchanged tasks are left alone.
+pub async fn apply(entries: Vec<TaskEntry>, profile: Option<String>) {
+    // a reload during the shutdown would start the tasks again
+    if shutdown::is_stopping() { return }
+    let mut current = CURRENT.lock().await;
+    *current = (entries, profile);
+    let (entries, profile) = &*current;
+    if let Some(profile) = profile {
+        info!("Profile {}", profile);
+    }
+
+    let entries: Vec<&TaskEntry> = entries.iter().filter(|entry| entry.in_profile(profile.as_deref())).collect();
+    let mut tasks = TASKS.lock().await;
+    tasks.retain(|name, (task, handle)| {
+        let keep = entries.iter().any(|entry| entry.name() == name && entry.task == *task);
+        if !keep {
+            info!("Stop task {}", name);
+            if let Some(handle) = handle.take() { stop(name, &handle) }
+            HEALTH.lock().unwrap().remove(name);
+        }
+        keep
+    });
+    for entry in entries {
+        if tasks.contains_key(entry.name()) { continue }
+        info!("Start task {}", entry.name());
+        let handle = spawn(entry.name(), &entry.task);
+        tasks.insert(entry.name().to_string(), (entry.task.clone(), Some(handle)));
+    }
+}
+
+
+/// Run the tasks of another profile, `None` for only the tasks without profiles.
+///
+/// ## Errors
+///
+/// Returns an `Err` if no task is in the profile.
+pub async fn switch_profile(profile: Option<String>) -> Result<(), String> {
+    let entries = CURRENT.lock().await.0.clone();
+    if let Some(profile) = &profile {
+        if !entries.iter().any(|entry| entry.profiles.contains(profile)) {
+            return Err(format!("no task is in profile `{}`", profile));
+        }
+    }
+    apply(entries, profile).await;
+    Ok(())
+}
+
+
+/// The active profile.
+pub async fn profile() -> Option<String> {
+    CURRENT.lock().await.1.clone()
+}
+
+
+/// The tasks of the config, and if they are running.
+pub async fn list() -> Vec<TaskInfo> {
+    let current = CURRENT.lock().await;
+    let tasks = TASKS.lock().await;
+    current.0.iter().map(|entry| TaskInfo {
+        name: entry.name().to_string(),
+        type_name: entry.task.type_name(),
+        profiles: entry.profiles.clone(),
+        running: tasks.get(entry.name()).is_some_and(|(_, handle)| handle.is_some()),
+        health: HEALTH.lock().unwrap().get(entry.name()).cloned(),
+    }).collect()
+}
+
+
+/// Stop the named task if it is running, otherwise start it again.
+///
+/// ## Errors
+///
+/// Returns an `Err` if no task has the name, or it is not in the active profile.
+pub async fn toggle(name: &str) -> Result<(), String> {
+    if shutdown::is_stopping() {
+        return Err("macrokey is shutting down".to_string());
+    }
+    let mut tasks = TASKS.lock().await;
+    let Some((task, handle)) = tasks.get_mut(name) else {
+        return Err(format!("no running task named `{}`", name));
+    };
+    match handle.take() {
+        Some(handle) => {
+            info!("Stop task {}", name);
+            stop(name, &handle);
+        }
+        None => {
+            info!("Start task {}", name);
+            *handle = Some(spawn(name, task));
+        }
+    }
+    Ok(())
+}
+
+
+/// Run the task in the background under a [`supervise`]or, with its name available to it as [`TASK_NAME`].
+fn spawn(name: &str, task: &TaskConfig) -> JoinHandle<()> {
+    HEALTH.lock().unwrap().insert(name.to_string(), Health::default());
+    tokio::spawn(TASK_NAME.scope(name.to_string(), supervise(name.to_string(), task.clone())))
+}
+
+
+/// Stop all running tasks, for the shutdown.
+///
+/// Waits until each task is dropped. The futures reading its devices are only
+/// aborted with it, the runtime drops them later, which closes and so ungrabs
+/// the devices. At the latest they are closed when the process exits.
+pub async fn stop_all() {
+    let handles: Vec<JoinHandle<()>> = TASKS.lock().await.iter_mut()
+        .filter_map(|(name, (_, handle))| {
+            let handle = handle.take()?;
+            info!("Stop task {}", name);
+            stop(name, &handle);
+            Some(handle)
+        })
+        .collect();
+    for handle in handles {
+        let _ = handle.await;
+    }
+}
+
+
+fn stop(name: &str, handle: &JoinHandle<()>) {
+    handle.abort();
+    if let Some(health) = HEALTH.lock().unwrap().get_mut(name) {
+        health.status = Status::Stopped;
+    }
+}
+
+
+/// Run the task, restarting it when it fails or panics.
+///
+/// The wait before a restart starts at [`MIN_BACKOFF`] and doubles up to
+/// [`MAX_BACKOFF`], so a task which keeps failing does not spin. The restarts and
+/// last error are kept in the [`Health`] of the task. Panics are only caught
+/// when built to unwind, the release profile aborts on a panic.
+///
+/// A [fatal](error::Error::is_fatal) error is not restarted, macrokey shuts down
+/// instead. A task which ended without an error is not restarted either, macrokey
+/// keeps running when every task ended, as a reload or the control socket can start tasks.
+async fn supervise(name: String, task: TaskConfig) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let devices = task.devices();
+        if !devices.is_empty() && !evdev::enumerate().any(|(_, device)| devices.iter().any(|m| m.matches(&device))) {
+            info!("Task {} waits for its device to be plugged in", name);
+        }
+
+        let started = Instant::now();
+        let error = match AssertUnwindSafe(task.clone().run()).catch_unwind().await {
+            Ok(Ok(())) => {
+                info!("Task {} finished", name);
+                set_health(&name, |health| health.status = Status::Finished);
+                if !any_running().await {
+                    info!("Every task ended, waiting for a reload or the control socket");
+                }
+                return;
+            }
+            Ok(Err(e)) if e.is_fatal() => {
+                set_health(&name, |health| {
+                    health.status = Status::Failed;
+                    health.last_error = Some(e.to_string());
+                });
+                shutdown::fail(format!("task {} failed: {}", name, e));
+                return;
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(panic) => format!("panicked: {}", functions::panic_message(&panic)),
+        };
+
+        if started.elapsed() >= MAX_BACKOFF { backoff = MIN_BACKOFF }
+        set_health(&name, |health| {
+            health.status = Status::Restarting;
+            health.restarts += 1;
+            health.last_error = Some(error.clone());
+        });
+        error!("Task {} failed: {}, restarting in {}s", name, error, backoff.as_secs());
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        set_health(&name, |health| health.status = Status::Running);
+    }
+}
+
+
+/// Returns true if a task of the config is running or will be restarted.
+async fn any_running() -> bool {
+    let tasks = TASKS.lock().await;
+    let health = HEALTH.lock().unwrap();
+    tasks.iter()
+        .filter(|(_, (_, handle))| handle.is_some())
+        .filter_map(|(name, _)| health.get(name))
+        .any(|health| matches!(health.status, Status::Running | Status::Restarting))
+}
+
+
+fn set_health(name: &str, update: impl FnOnce(&mut Health)) {
+    if let Some(health) = HEALTH.lock().unwrap().get_mut(name) {
+        update(health);
+    }
+}
@@ -1,45 +1,83 @@
 use crate::{
-        functions,
-        key_event_type::KeyEventType,
-    };
-use evdev::Device;
+    device_matcher::DeviceMatcher,
+    devices,
+    error::{
+        self,
+        Error,
+    },
+    input::InputSource,
+    key_event_type::KeyEventType,
+    replay::Player,
+    signals::VirtualDeviceTx,
+    tasks::Task,
+};
+use evdev::{
+    Device,
+};
+use serde::Deserialize;
 
 const TASK_ID: &str = "MONITOR";
 
-/// Monitors and logs all events from all devices matching the given regex.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The devices to monitor, all devices by default.
+    pub device: DeviceMatcher,
+    /// Log every event, not just key presses.
+    pub all_values: bool,
+}
+
+/// Monitors and logs all events from all devices matching the config.
 ///
 /// Events are logged at the INFO level with the format:
 /// `{device_name}: {event}`
 ///
-/// The task will exit if no devices are found matching the given regex.
-///
-/// ## Arguments
-///
-/// * `device_name`: A regex to match the device name against.
+/// Devices plugged in later are monitored too.
 ///
 /// ## Examples
 ///
-/// Log all events from all devices with "" (anything) in their name.
-pub async fn task(device_name: &str) {
+/// Log all events from all devices with `device = ""` (anything) in their name.
+pub async fn task(config: Config) -> error::Result<()> {
     info!("{}", TASK_ID);
-    let devices = functions::get_devices_by_regex(device_name);
-    if devices.len() == 0 {
-        info!("{} No devices found matching: {}", TASK_ID, device_name);
-        return;
+    devices::for_each(|device| {
+        let matched = config.device.matches(&device);
+        matched.then(|| monitor_events(device, config.all_values))
+    }).await
+}
+
+impl Task for Config {
+    fn type_name(&self) -> &'static str {
+        "monitor"
     }
 
-    for device in devices {
-        tokio::spawn(monitor_events(device));
+    fn devices(&self) -> Vec<&DeviceMatcher> {
+        vec![&self.device]
+    }
+
+    async fn run(self) -> error::Result<()> {
+        task(self).await
+    }
+
+    async fn replay(self, events: Player, _tx: VirtualDeviceTx) -> error::Result<()> {
+        let device_name = events.device().name.clone();
+        log_events(events, &device_name, self.all_values).await;
+        Ok(())
     }
 }
 
 
-async fn monitor_events(device: Device) {
+async fn monitor_events(device: Device, all_values: bool) -> error::Result<()> {
     let device_name = device.name().unwrap_or("<unnamed>").to_string();
-    let mut events = device.into_event_stream().unwrap();
-    while let Ok(ev) = events.next_event().await {
-        if ev.value() != KeyEventType::PRESSED { continue; }
+    let events = device.into_event_stream().map_err(|e| Error::Device { name: device_name.clone(), source: e })?;
+    log_events(events, &device_name, all_values).await;
+    Ok(())
+}
+
+
+async fn log_events(mut events: impl InputSource, device_name: &str, all_values: bool) {
+    while let Some(ev) = events.next_event().await {
+        if !all_values && ev.value() != KeyEventType::PRESSED { continue; }
         info!("{}: {:?}", device_name, ev.destructure()); // use just ev if you want the number instead of the code
     }
-    info!("Error reading event from {}", device_name);
+    info!("Stopped reading events from {}", device_name);
 }
\ No newline at end of file
@@ -0,0 +1,322 @@
+use evdev::{
+    EventType,
+    InputEvent,
+    KeyCode,
+};
+use serde::Deserialize;
+use std::collections::HashSet;
+use crate::{
+    action::Action,
+    chord::Key,
+    device_matcher::DeviceMatcher,
+    devices,
+    error::{
+        self,
+        Error,
+    },
+    replay::Player,
+    signals::VirtualDeviceTx,
+    stage::{
+        self,
+        Pipeline,
+        Stage,
+    },
+    tasks::{
+        hotkeys,
+        macros,
+        remap,
+        Task,
+    },
+};
+
+const TASK_ID: &str = "PIPELINE";
+
+/// Pass the events of a device through stages in order, then to the virtual device.
+///
+/// Each stage sees the events the stage before it passed on, so a remap before
+/// hotkeys makes the hotkeys use the remapped keys. Stages take the same keys as
+/// their task, except `device` and `grab` which are set on the pipeline.
+///
+/// ## Example
+///
+/// ```toml
+/// [[task]]
+/// type = "pipeline"
+/// device = { name = "AT Translated Set 2 keyboard" }
+///
+/// [[task.stage]]
+/// type = "filter"
+/// drop = ["INSERT"]
+///
+/// [[task.stage]]
+/// type = "remap"
+/// map = { CAPSLOCK = "esc" }
+///
+/// [[task.stage.layer]]
+/// name = "nav"
+/// hold = "RIGHTALT"
+/// map = { H = "left", J = "down", K = "up", L = "right" }
+///
+/// [[task.stage]]
+/// type = "hotkeys"
+///
+/// [[task.stage.binding]]
+/// chord = "ctrl+shift+z"
+/// action = { command = "wlr-which-key" }
+///
+/// [[task.stage]]
+/// type = "macros"
+/// record = [{ chord = "ctrl+alt+1", name = "farm" }]
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The devices to run the stages for, each device has stages of its own.
+    pub device: DeviceMatcher,
+    /// Grab the devices so applications only see what comes out of the last stage.
+    pub grab: bool,
+    #[serde(rename = "stage")]
+    pub stages: Vec<StageConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            device: DeviceMatcher::name_regex("keyboard").unwrap(),
+            grab: true,
+            stages: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, stage) in self.stages.iter().enumerate() {
+            stage.validate().map_err(|e| format!("stage {} ({}): {}", i + 1, stage.type_name(), e))?;
+        }
+        Ok(())
+    }
+
+    /// The actions of every stage.
+    pub fn actions(&self) -> Vec<&Action> {
+        self.stages.iter().flat_map(StageConfig::actions).collect()
+    }
+
+    /// A new pipeline, for a device.
+    fn pipeline(&self) -> Result<Pipeline, String> {
+        let stages = self.stages.iter().map(StageConfig::build).collect::<Result<_, _>>()?;
+        Ok(Pipeline::new(stages))
+    }
+}
+
+
+/// A stage of the pipeline, selected by its `type` key.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StageConfig {
+    Filter(Filter),
+    Remap(Box<remap::Table>),
+    Hotkeys(hotkeys::Config),
+    Macros(macros::Config),
+}
+
+impl StageConfig {
+    /// The `type` key of this stage in the config file.
+    fn type_name(&self) -> &'static str {
+        match self {
+            StageConfig::Filter(_) => "filter",
+            StageConfig::Remap(_) => "remap",
+            StageConfig::Hotkeys(_) => "hotkeys",
+            StageConfig::Macros(_) => "macros",
+        }
+    }
+
+    /// The task configs are reused, the pipeline picks the device and grabs it.
+    fn validate(&self) -> Result<(), String> {
+        let own_device = match self {
+            StageConfig::Filter(_) => false,
+            StageConfig::Remap(table) => table.device != remap::Table::default().device || !table.grab,
+            StageConfig::Hotkeys(config) => config.device != hotkeys::Config::default().device || config.grab,
+            StageConfig::Macros(config) => config.device != macros::Config::default().device,
+        };
+        if own_device {
+            return Err("`device` and `grab` are set on the pipeline, not its stages".to_string());
+        }
+        match self {
+            StageConfig::Filter(_) => Ok(()),
+            StageConfig::Remap(table) => table.validate(),
+            StageConfig::Hotkeys(config) => config.validate(),
+            StageConfig::Macros(config) => config.validate(),
+        }
+    }
+
+    fn actions(&self) -> Vec<&Action> {
+        match self {
+            StageConfig::Remap(table) => table.actions(),
+            StageConfig::Hotkeys(config) => config.bindings.iter().map(|b| &b.action).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn build(&self) -> Result<Box<dyn Stage>, String> {
+        match self {
+            StageConfig::Filter(filter) => Ok(Box::new(filter.clone())),
+            StageConfig::Remap(table) => Ok(remap::stage(table.as_ref().clone())),
+            StageConfig::Hotkeys(config) => hotkeys::stage(config),
+            StageConfig::Macros(config) => Ok(macros::stage(config.record.clone())),
+        }
+    }
+}
+
+
+/// Drops keys, events which are not keys are always passed on.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+    /// Keys that are dropped.
+    pub drop: HashSet<Key>,
+    /// If given, only these keys are passed on.
+    pub only: HashSet<Key>,
+}
+
+impl Stage for Filter {
+    fn process(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        if ev.event_type() != EventType::KEY {
+            return vec![ev];
+        }
+        let key = Key(KeyCode::new(ev.code()));
+        let pass = !self.drop.contains(&key) && (self.only.is_empty() || self.only.contains(&key));
+        if pass { vec![ev] } else { Vec::new() }
+    }
+}
+
+
+pub async fn task(config: Config) -> error::Result<()> {
+    info!("{}", TASK_ID);
+    config.pipeline().map_err(Error::Config)?;
+    devices::for_each(|device| {
+        if !config.device.matches(&device) { return None }
+        // checked above, so each device gets its stages
+        let pipeline = config.pipeline().ok()?;
+        Some(stage::run(device, config.grab, pipeline))
+    }).await
+}
+
+impl Task for Config {
+    fn type_name(&self) -> &'static str {
+        "pipeline"
+    }
+
+    fn devices(&self) -> Vec<&DeviceMatcher> {
+        vec![&self.device]
+    }
+
+    async fn run(self) -> error::Result<()> {
+        task(self).await
+    }
+
+    async fn replay(self, events: Player, tx: VirtualDeviceTx) -> error::Result<()> {
+        let pipeline = self.pipeline().map_err(Error::Config)?;
+        stage::forward(events, tx, self.grab, pipeline).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use evdev::RelativeAxisCode;
+    use super::*;
+    use crate::memory::{
+        self,
+        MemorySource,
+    };
+
+    async fn run(toml: &str, source: MemorySource) -> memory::MemorySink {
+        let config: Config = toml::from_str(toml).unwrap();
+        config.validate().unwrap();
+        memory::run(config.pipeline().unwrap(), source).await
+    }
+
+    #[tokio::test]
+    async fn filter_passes_events_which_are_not_keys() {
+        let toml = r#"
+            [[stage]]
+            type = "filter"
+            only = ["A"]
+        "#;
+        let source = MemorySource::new()
+            .tap(KeyCode::KEY_A)
+            .tap(KeyCode::KEY_B)
+            .event(InputEvent::new_now(EventType::RELATIVE.0, RelativeAxisCode::REL_X.0, 5))
+            .sync();
+        let sink = run(toml, source).await;
+        assert_eq!(sink.keys(), [(KeyCode::KEY_A, 1), (KeyCode::KEY_A, 0)]);
+        // 3 frames, the frames of B are dropped whole
+        assert_eq!(sink.events().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn stages_see_the_keys_of_the_stage_before() {
+        let toml = r#"
+            [[stage]]
+            type = "remap"
+            map = { CAPSLOCK = "esc" }
+
+            [[stage]]
+            type = "filter"
+            drop = ["CAPSLOCK", "A"]
+        "#;
+        let source = MemorySource::new()
+            .tap(KeyCode::KEY_CAPSLOCK)
+            .tap(KeyCode::KEY_A);
+        let sink = run(toml, source).await;
+        assert_eq!(sink.keys(), [(KeyCode::KEY_ESC, 1), (KeyCode::KEY_ESC, 0)]);
+    }
+
+    #[tokio::test]
+    async fn frames_of_the_device_stay_whole() {
+        let toml = r#"
+            [[stage]]
+            type = "remap"
+            map = { A = "c" }
+        "#;
+        let key = |key: KeyCode, value| InputEvent::new_now(EventType::KEY.0, key.code(), value);
+        // A is rolled over to B in one frame
+        let source = MemorySource::new()
+            .press(KeyCode::KEY_A)
+            .event(key(KeyCode::KEY_A, 0))
+            .event(key(KeyCode::KEY_B, 1))
+            .sync()
+            .release(KeyCode::KEY_B);
+        let sink = run(toml, source).await;
+        assert_eq!(sink.key_frames(), [
+            vec![(KeyCode::KEY_C, 1)],
+            vec![(KeyCode::KEY_C, 0), (KeyCode::KEY_B, 1)],
+            vec![(KeyCode::KEY_B, 0)],
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timed_out_events_go_through_later_stages() {
+        let toml = r#"
+            [[stage]]
+            type = "hotkeys"
+            timeout = 500
+
+            [[stage.binding]]
+            sequence = "a b"
+            action = { keys = "x" }
+
+            [[stage]]
+            type = "remap"
+            map = { A = "c" }
+        "#;
+        let source = MemorySource::new()
+            .tap(KeyCode::KEY_A)
+            .wait(1000);
+        let sink = run(toml, source).await;
+        assert_eq!(sink.timed_keys(), [(500, KeyCode::KEY_C, 1), (500, KeyCode::KEY_C, 0)]);
+        // the replayed tap is two frames, so the key is seen down
+        assert_eq!(sink.key_frames(), [vec![(KeyCode::KEY_C, 1)], vec![(KeyCode::KEY_C, 0)]]);
+    }
+}
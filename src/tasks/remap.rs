@@ -0,0 +1,770 @@
+use evdev::{
+    EventType,
+    InputEvent,
+    KeyCode,
+};
+use serde::Deserialize;
+use std::collections::{
+    HashMap,
+    HashSet,
+    VecDeque,
+};
+use tokio::{
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use crate::{
+    action::Action,
+    chord::{
+        Chord,
+        Key,
+    },
+    device_matcher::DeviceMatcher,
+    devices,
+    error,
+    key_event_type::KeyEventType,
+    replay::Player,
+    signals::VirtualDeviceTx,
+    stage::{
+        self,
+        Pipeline,
+        Stage,
+    },
+    tasks::Task,
+};
+
+const TASK_ID: &str = "REMAP";
+
+/// Remap keys of one or more devices onto the virtual device.
+///
+/// A device such as a remote often shows up as several devices, each gets the
+/// first table whose `device` matches it.
+///
+/// ## Example
+///
+/// ```toml
+/// [[task]]
+/// type = "remap"
+///
+/// [[task.device]]
+/// device = "^Usb Audio Device"
+/// map = { F2 = "leftmeta", MAIL = "alt+tab" }
+/// drop = ["HOMEPAGE"]
+/// action = { POWER = { command = "systemctl suspend" } }
+///
+/// [[task.device.layer]]
+/// name = "nav"
+/// hold = "CAPSLOCK"
+/// map = { H = "left", J = "down", K = "up", L = "right" }
+///
+/// [task.device.tap_hold]
+/// CAPSLOCK = { tap = "esc", hold = "leftctrl" }
+///
+/// [[task.device.combo]]
+/// keys = ["J", "K"]
+/// chord = "esc"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "device")]
+    pub devices: Vec<Table>,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        self.devices.iter().try_for_each(Table::validate)
+    }
+
+    /// The actions of every table and layer.
+    pub fn actions(&self) -> Vec<&Action> {
+        self.devices.iter().flat_map(Table::actions).collect()
+    }
+}
+
+/// The remap table of a device.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Table {
+    /// The devices this table is for.
+    pub device: DeviceMatcher,
+    /// Grab the device so only the remapped events reach applications.
+    pub grab: bool,
+    /// Keys sent as another key, or as a chord held while the key is held.
+    pub map: HashMap<Key, Chord>,
+    /// Keys that run an action when pressed instead of being sent.
+    pub action: HashMap<Key, Action>,
+    /// Keys that are never sent.
+    pub drop: HashSet<Key>,
+    /// Send events that are not mapped, otherwise only mapped keys are sent.
+    pub passthrough: bool,
+    /// Layers on top of this table, later layers have priority over earlier ones.
+    #[serde(rename = "layer")]
+    pub layers: Vec<Layer>,
+    /// Keys that send one chord when tapped and another when held.
+    pub tap_hold: HashMap<Key, TapHold>,
+    /// Default ms a tap-hold key must be held to count as held.
+    pub tapping_term: u64,
+    /// A tap-hold key counts as held as soon as another key is pressed and released while it is down.
+    pub permissive_hold: bool,
+    /// Keys pressed together to send a chord or run an action.
+    #[serde(rename = "combo")]
+    pub combos: Vec<Combo>,
+    /// Default ms to wait for the rest of the keys of a combo.
+    pub combo_term: u64,
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Table {
+            device: DeviceMatcher::default(),
+            grab: true,
+            map: HashMap::new(),
+            action: HashMap::new(),
+            drop: HashSet::new(),
+            passthrough: true,
+            layers: Vec::new(),
+            tap_hold: HashMap::new(),
+            tapping_term: 200,
+            permissive_hold: false,
+            combos: Vec::new(),
+            combo_term: 50,
+        }
+    }
+}
+
+impl Table {
+    pub fn validate(&self) -> Result<(), String> {
+        for layer in self.layers.iter() {
+            if layer.hold.is_none() && layer.toggle.is_none() {
+                return Err(format!("layer `{}` needs a hold or toggle key", layer.name));
+            }
+        }
+        self.combos.iter().try_for_each(Combo::validate)
+    }
+
+    /// The actions of the table, its layers and combos.
+    pub fn actions(&self) -> Vec<&Action> {
+        self.action.values()
+            .chain(self.layers.iter().flat_map(|layer| layer.action.values()))
+            .chain(self.combos.iter().filter_map(|combo| combo.action.as_ref()))
+            .collect()
+    }
+
+    fn lookup(&self, key: Key) -> Option<Output> {
+        lookup(&self.map, &self.action, &self.drop, key)
+    }
+}
+
+
+/// Keys of a table which are used while the layer is active.
+///
+/// Keys the layer does not have fall through to the active layers below it,
+/// then to the table.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Layer {
+    pub name: String,
+    /// Active while this key is held.
+    pub hold: Option<Key>,
+    /// Each press of this key turns the layer on or off.
+    pub toggle: Option<Key>,
+    #[serde(default)]
+    pub map: HashMap<Key, Chord>,
+    #[serde(default)]
+    pub action: HashMap<Key, Action>,
+    #[serde(default)]
+    pub drop: HashSet<Key>,
+}
+
+impl Layer {
+    fn lookup(&self, key: Key) -> Option<Output> {
+        lookup(&self.map, &self.action, &self.drop, key)
+    }
+}
+
+
+/// A dual-role key, e.g. escape when tapped and ctrl when held.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TapHold {
+    pub tap: Chord,
+    pub hold: Chord,
+    /// Overrides the `tapping_term` of the table.
+    pub tapping_term: Option<u64>,
+}
+
+
+/// Ordinary keys which do something else when pressed together, e.g. J and K for escape.
+///
+/// The first key is held back for the combo term. If the other keys are not
+/// pressed in time, the held back keys are sent in the order they were pressed.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Combo {
+    pub keys: Vec<Key>,
+    /// Held while the keys are held.
+    pub chord: Option<Chord>,
+    /// Run when the keys are pressed.
+    pub action: Option<Action>,
+    /// Overrides the `combo_term` of the table.
+    pub term: Option<u64>,
+}
+
+impl Combo {
+    /// The keys joined with `+`, for logs and errors.
+    fn name(&self) -> String {
+        self.keys.iter().map(|key| format!("{:?}", key.0)).collect::<Vec<_>>().join("+")
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let keys = HashSet::<&Key>::from_iter(self.keys.iter());
+        if self.keys.len() < 2 || keys.len() != self.keys.len() {
+            return Err(format!("combo `{}` needs two or more different keys", self.name()));
+        }
+        if self.chord.is_some() == self.action.is_some() {
+            return Err(format!("combo `{}` needs either a chord or an action", self.name()));
+        }
+        Ok(())
+    }
+}
+
+
+/// The keys of a combo pressed so far, and the events held back since the first.
+struct PendingCombo {
+    keys: Vec<KeyCode>,
+    deadline: Instant,
+    buffer: Vec<InputEvent>,
+}
+
+
+/// A tap-hold key which is down and not yet decided, with the events that came after it.
+struct Pending {
+    key: KeyCode,
+    tap_hold: TapHold,
+    deadline: Instant,
+    buffer: Vec<InputEvent>,
+}
+
+
+/// What a key does, decided when it is pressed.
+#[derive(Clone, Debug)]
+enum Output {
+    Chord(Chord),
+    Action(Action),
+    /// Not mapped, sent as is if the table passes keys through.
+    Pass,
+    /// Holds the layer with this index.
+    Layer(usize),
+    /// A key of the combo with this index.
+    Combo(usize),
+    /// Nothing is sent.
+    Drop,
+}
+
+fn lookup(map: &HashMap<Key, Chord>, action: &HashMap<Key, Action>, drop: &HashSet<Key>, key: Key) -> Option<Output> {
+    if let Some(action) = action.get(&key) { return Some(Output::Action(action.clone())) }
+    if drop.contains(&key) { return Some(Output::Drop) }
+    map.get(&key).map(|chord| Output::Chord(*chord))
+}
+
+
+/// Runs a remap for each device matching one of the tables, including devices plugged in later.
+pub async fn task(config: Config) -> error::Result<()> {
+    info!("{}", TASK_ID);
+    devices::for_each(|device| {
+        // first table wins when several match the same device
+        let table = config.devices.iter().find(|table| table.device.matches(&device))?;
+        Some(stage::run(device, table.grab, Pipeline::new(vec![stage(table.clone())])))
+    }).await
+}
+
+impl Task for Config {
+    fn type_name(&self) -> &'static str {
+        "remap"
+    }
+
+    fn devices(&self) -> Vec<&DeviceMatcher> {
+        self.devices.iter().map(|table| &table.device).collect()
+    }
+
+    async fn run(self) -> error::Result<()> {
+        task(self).await
+    }
+
+    async fn replay(self, events: Player, tx: VirtualDeviceTx) -> error::Result<()> {
+        let Some(table) = self.devices.into_iter().find(|table| table.device.matches(events.device())) else { return Ok(()) };
+        stage::forward(events, tx, table.grab, Pipeline::new(vec![stage(table)])).await
+    }
+}
+
+
+/// The table as a stage of a pipeline.
+pub fn stage(table: Table) -> Box<dyn Stage> {
+    Box::new(State::new(table))
+}
+
+
+/// The remap state of a device.
+struct State {
+    table: Table,
+    /// Layers held by their hold key.
+    held: Vec<bool>,
+    /// Layers turned on by their toggle key.
+    toggled: Vec<bool>,
+    /// What each pressed key does, so its repeats and release match its press when layers change.
+    pressed: HashMap<KeyCode, Output>,
+    pending: Option<Pending>,
+    combo: Option<PendingCombo>,
+    /// Combos whose chord is held.
+    active_combos: HashSet<usize>,
+    /// Actions of keys and combos pressed, run once the events before them are sent.
+    actions: Vec<Action>,
+}
+
+impl Stage for State {
+    fn process(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        self.process_input(ev)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        State::deadline(self)
+    }
+
+    // the tapping term ran out without the key being released
+    fn timeout(&mut self) -> Vec<InputEvent> {
+        State::timeout(self)
+    }
+
+    fn actions(&mut self) -> Vec<Action> {
+        std::mem::take(&mut self.actions)
+    }
+}
+
+/// An event waiting to be processed, and whether it may start a combo.
+type Queued = (InputEvent, bool);
+
+impl State {
+    fn new(table: Table) -> Self {
+        let layers = table.layers.len();
+        State {
+            table,
+            held: vec![false; layers],
+            toggled: vec![false; layers],
+            pressed: HashMap::new(),
+            pending: None,
+            combo: None,
+            active_combos: HashSet::new(),
+            actions: Vec::new(),
+        }
+    }
+
+
+    /// When the pending tap-hold key becomes held, or the pending combo gives up.
+    fn deadline(&self) -> Option<Instant> {
+        let tap_hold = self.pending.as_ref().map(|pending| pending.deadline);
+        let combo = self.combo.as_ref().map(|combo| combo.deadline);
+        tap_hold.into_iter().chain(combo).min()
+    }
+
+    /// The events to send for an input event.
+    ///
+    /// While a tap-hold key or combo is undecided the events after it are held
+    /// back, and sent in order once it is known what the keys do.
+    fn process_input(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        let mut queue = VecDeque::from([(ev, true)]);
+        let mut output = self.expire(&mut queue);
+        output.extend(self.run(queue));
+        output
+    }
+
+    /// The tapping term or combo term ran out.
+    fn timeout(&mut self) -> Vec<InputEvent> {
+        let mut queue = VecDeque::new();
+        let mut output = self.expire(&mut queue);
+        output.extend(self.run(queue));
+        output
+    }
+
+    /// Decide what is pending past its deadline, the events held back are processed next.
+    fn expire(&mut self, queue: &mut VecDeque<Queued>) -> Vec<InputEvent> {
+        let now = Instant::now();
+        let mut output = Vec::new();
+        if self.pending.as_ref().is_some_and(|pending| pending.deadline <= now) {
+            output.extend(self.hold(queue));
+        }
+        if self.combo.as_ref().is_some_and(|combo| combo.deadline <= now) {
+            self.cancel_combo(queue);
+        }
+        output
+    }
+
+    fn run(&mut self, mut queue: VecDeque<Queued>) -> Vec<InputEvent> {
+        let mut output = Vec::new();
+        while let Some((ev, can_combo)) = queue.pop_front() {
+            if self.combo.is_some() {
+                output.extend(self.combo_step(ev, &mut queue));
+                continue;
+            }
+
+            let Some(pending) = self.pending.as_mut() else {
+                output.extend(self.process_event(ev, can_combo));
+                continue;
+            };
+
+            if ev.event_type() == EventType::KEY && ev.code() == pending.key.code() {
+                // released within the tapping term, repeats are ignored until decided
+                if ev.value() == KeyEventType::RELEASED {
+                    let tap_hold = pending.tap_hold;
+                    let buffer = std::mem::take(&mut pending.buffer);
+                    self.pending = None;
+                    output.extend(tap_hold.tap.events());
+                    requeue(&mut queue, buffer, true);
+                }
+                continue;
+            }
+
+            // another key tapped while the tap-hold key is down
+            let permissive = self.table.permissive_hold
+                && ev.event_type() == EventType::KEY
+                && ev.value() == KeyEventType::RELEASED
+                && pending.buffer.iter().any(|b| {
+                    b.event_type() == EventType::KEY && b.code() == ev.code() && b.value() == KeyEventType::PRESSED
+                });
+            pending.buffer.push(ev);
+            if permissive {
+                output.extend(self.hold(&mut queue));
+            }
+        }
+        output
+    }
+
+    /// Decide the pending key is held, the events held back are processed next.
+    fn hold(&mut self, queue: &mut VecDeque<Queued>) -> Vec<InputEvent> {
+        let Some(pending) = self.pending.take() else { return Vec::new() };
+        self.pressed.insert(pending.key, Output::Chord(pending.tap_hold.hold));
+        requeue(queue, pending.buffer, true);
+        pending.tap_hold.hold.follow(KeyEventType::PRESSED.into())
+    }
+
+    /// Add an event to the pending combo, firing it once all of its keys are down.
+    fn combo_step(&mut self, ev: InputEvent, queue: &mut VecDeque<Queued>) -> Vec<InputEvent> {
+        let Some(combo) = self.combo.as_mut() else { return Vec::new() };
+        let key = KeyCode::new(ev.code());
+        if ev.event_type() != EventType::KEY {
+            combo.buffer.push(ev);
+            return Vec::new();
+        }
+
+        if ev.value() == KeyEventType::PRESSED {
+            let mut keys = combo.keys.clone();
+            keys.push(key);
+            let mut candidates = self.table.combos.iter()
+                .enumerate()
+                .filter(|(_, c)| keys.iter().all(|k| c.keys.contains(&Key(*k))))
+                .peekable();
+            if candidates.peek().is_some() {
+                let complete = candidates.find(|(_, c)| c.keys.len() == keys.len()).map(|(index, _)| index);
+                combo.keys = keys;
+                combo.buffer.push(ev);
+                return match complete {
+                    Some(index) => self.fire_combo(index),
+                    None => Vec::new(),
+                };
+            }
+        } else if ev.value() == KeyEventType::REPEAT && combo.keys.contains(&key) {
+            return Vec::new();
+        }
+
+        // not part of a combo, send the held back keys as they are
+        queue.push_front((ev, true));
+        self.cancel_combo(queue);
+        Vec::new()
+    }
+
+    fn fire_combo(&mut self, index: usize) -> Vec<InputEvent> {
+        let Some(pending) = self.combo.take() else { return Vec::new() };
+        let combo = &self.table.combos[index];
+        info!("{} combo {}", TASK_ID, combo.name());
+        for key in pending.keys {
+            self.pressed.insert(key, Output::Combo(index));
+        }
+        if let Some(action) = &combo.action {
+            self.actions.push(action.clone());
+        }
+        match combo.chord {
+            Some(chord) => {
+                self.active_combos.insert(index);
+                chord.follow(KeyEventType::PRESSED.into())
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// The held back events are processed next, they can not start another combo.
+    fn cancel_combo(&mut self, queue: &mut VecDeque<Queued>) {
+        if let Some(combo) = self.combo.take() {
+            requeue(queue, combo.buffer, false);
+        }
+    }
+
+
+    /// The events to send for an event, once nothing is pending.
+    fn process_event(&mut self, ev: InputEvent, can_combo: bool) -> Vec<InputEvent> {
+        // log
+        //if ev.event_type() == EventType::KEY && ev.value() == KeyEventType::PRESSED { info!("{}: {:?}", self.table.device, ev.destructure()); };
+
+        if ev.event_type() != EventType::KEY {
+            return self.pass(ev);
+        }
+
+        let key = KeyCode::new(ev.code());
+        if ev.value() == KeyEventType::PRESSED {
+            let combo_term = self.table.combos.iter()
+                .filter(|combo| combo.keys.contains(&Key(key)))
+                .map(|combo| combo.term.unwrap_or(self.table.combo_term))
+                .min();
+            if let Some(term) = combo_term.filter(|_| can_combo) {
+                self.combo = Some(PendingCombo {
+                    keys: vec![key],
+                    deadline: Instant::now() + Duration::from_millis(term),
+                    buffer: vec![ev],
+                });
+                return Vec::new();
+            }
+
+            if let Some(tap_hold) = self.table.tap_hold.get(&Key(key)) {
+                let term = tap_hold.tapping_term.unwrap_or(self.table.tapping_term);
+                self.pending = Some(Pending {
+                    key,
+                    tap_hold: *tap_hold,
+                    deadline: Instant::now() + Duration::from_millis(term),
+                    buffer: Vec::new(),
+                });
+                return Vec::new();
+            }
+        }
+
+        let output = match KeyEventType::from(ev.value()) {
+            KeyEventType::PRESSED => {
+                let output = self.press(key);
+                self.pressed.insert(key, output.clone());
+                output
+            }
+            // keys pressed before we started are looked up as they are now
+            KeyEventType::RELEASED => self.pressed.remove(&key).unwrap_or_else(|| self.lookup(key)),
+            _ => self.pressed.get(&key).cloned().unwrap_or_else(|| self.lookup(key)),
+        };
+
+        match output {
+            Output::Chord(chord) => chord.follow(ev.value()),
+            Output::Action(action) => {
+                if ev.value() == KeyEventType::PRESSED {
+                    self.actions.push(action);
+                }
+                Vec::new()
+            }
+            Output::Pass => self.pass(ev),
+            Output::Layer(index) => {
+                if ev.value() == KeyEventType::RELEASED {
+                    self.held[index] = false;
+                    info!("{} layer {} off", TASK_ID, self.table.layers[index].name);
+                }
+                Vec::new()
+            }
+            Output::Combo(index) => {
+                // the chord is released with the first key of the combo
+                let active = match KeyEventType::from(ev.value()) {
+                    KeyEventType::RELEASED => self.active_combos.remove(&index),
+                    _ => self.active_combos.contains(&index),
+                };
+                match (active, self.table.combos[index].chord) {
+                    (true, Some(chord)) => chord.follow(ev.value()),
+                    _ => Vec::new(),
+                }
+            }
+            Output::Drop => Vec::new(),
+        }
+    }
+
+    fn pass(&self, ev: InputEvent) -> Vec<InputEvent> {
+        match self.table.passthrough {
+            true => vec![ev],
+            false => Vec::new(),
+        }
+    }
+
+    /// Layer keys change the active layers, other keys are looked up.
+    fn press(&mut self, key: KeyCode) -> Output {
+        for (index, layer) in self.table.layers.iter().enumerate() {
+            if layer.hold == Some(Key(key)) {
+                self.held[index] = true;
+                info!("{} layer {} on", TASK_ID, layer.name);
+                return Output::Layer(index);
+            }
+            if layer.toggle == Some(Key(key)) {
+                self.toggled[index] = !self.toggled[index];
+                info!("{} layer {} {}", TASK_ID, layer.name, if self.toggled[index] { "on" } else { "off" });
+                return Output::Drop;
+            }
+        }
+        self.lookup(key)
+    }
+
+    /// Look the key up in the active layers from the highest, then in the table.
+    fn lookup(&self, key: KeyCode) -> Output {
+        let key = Key(key);
+        self.table.layers.iter()
+            .enumerate()
+            .rev()
+            .filter(|(index, _)| self.held[*index] || self.toggled[*index])
+            .find_map(|(_, layer)| layer.lookup(key))
+            .or_else(|| self.table.lookup(key))
+            .unwrap_or(Output::Pass)
+    }
+}
+
+
+/// Put events back at the front of the queue, in their original order.
+fn requeue(queue: &mut VecDeque<Queued>, events: Vec<InputEvent>, can_combo: bool) {
+    for ev in events.into_iter().rev() {
+        queue.push_front((ev, can_combo));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use evdev::KeyCode;
+    use super::*;
+    use crate::memory::{
+        self,
+        MemorySource,
+    };
+
+    fn pipeline(toml: &str) -> Pipeline {
+        let table: Table = toml::from_str(toml).unwrap();
+        table.validate().unwrap();
+        Pipeline::new(vec![stage(table)])
+    }
+
+    #[tokio::test]
+    async fn map_sends_chord() {
+        let pipeline = pipeline(r#"map = { CAPSLOCK = "esc", F1 = "ctrl+c" }"#);
+        let source = MemorySource::new()
+            .tap(KeyCode::KEY_CAPSLOCK)
+            .tap(KeyCode::KEY_F1)
+            .tap(KeyCode::KEY_A);
+        let sink = memory::run(pipeline, source).await;
+        assert_eq!(sink.keys(), [
+            (KeyCode::KEY_ESC, 1),
+            (KeyCode::KEY_ESC, 0),
+            (KeyCode::KEY_LEFTCTRL, 1),
+            (KeyCode::KEY_C, 1),
+            (KeyCode::KEY_C, 0),
+            (KeyCode::KEY_LEFTCTRL, 0),
+            (KeyCode::KEY_A, 1),
+            (KeyCode::KEY_A, 0),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn layer_is_used_while_held() {
+        let pipeline = pipeline(r#"
+            [[layer]]
+            name = "nav"
+            hold = "RIGHTALT"
+            map = { H = "left" }
+        "#);
+        let source = MemorySource::new()
+            .press(KeyCode::KEY_RIGHTALT)
+            .press(KeyCode::KEY_H)
+            .release(KeyCode::KEY_RIGHTALT)
+            // released as it was pressed, though the layer is off
+            .release(KeyCode::KEY_H)
+            .tap(KeyCode::KEY_H);
+        let sink = memory::run(pipeline, source).await;
+        assert_eq!(sink.keys(), [
+            (KeyCode::KEY_LEFT, 1),
+            (KeyCode::KEY_LEFT, 0),
+            (KeyCode::KEY_H, 1),
+            (KeyCode::KEY_H, 0),
+        ]);
+    }
+
+    const TAP_HOLD: &str = r#"tap_hold = { CAPSLOCK = { tap = "esc", hold = "leftctrl" } }"#;
+
+    #[tokio::test(start_paused = true)]
+    async fn tap_hold_tapped() {
+        let source = MemorySource::new()
+            .press(KeyCode::KEY_CAPSLOCK)
+            .wait(100)
+            .release(KeyCode::KEY_CAPSLOCK);
+        let sink = memory::run(pipeline(TAP_HOLD), source).await;
+        assert_eq!(sink.timed_keys(), [(100, KeyCode::KEY_ESC, 1), (100, KeyCode::KEY_ESC, 0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tap_hold_held() {
+        let source = MemorySource::new()
+            .press(KeyCode::KEY_CAPSLOCK)
+            .wait(300)
+            .tap(KeyCode::KEY_C)
+            .release(KeyCode::KEY_CAPSLOCK);
+        let sink = memory::run(pipeline(TAP_HOLD), source).await;
+        assert_eq!(sink.timed_keys(), [
+            (200, KeyCode::KEY_LEFTCTRL, 1),
+            (300, KeyCode::KEY_C, 1),
+            (300, KeyCode::KEY_C, 0),
+            (300, KeyCode::KEY_LEFTCTRL, 0),
+        ]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keys_pressed_during_a_tap_come_after_it() {
+        let source = MemorySource::new()
+            .press(KeyCode::KEY_CAPSLOCK)
+            .wait(50)
+            .press(KeyCode::KEY_C)
+            .wait(50)
+            .release(KeyCode::KEY_CAPSLOCK)
+            .release(KeyCode::KEY_C);
+        let sink = memory::run(pipeline(TAP_HOLD), source).await;
+        assert_eq!(sink.timed_keys(), [
+            (100, KeyCode::KEY_ESC, 1),
+            (100, KeyCode::KEY_ESC, 0),
+            (100, KeyCode::KEY_C, 1),
+            (100, KeyCode::KEY_C, 0),
+        ]);
+    }
+
+    const COMBO: &str = r#"
+        [[combo]]
+        keys = ["J", "K"]
+        chord = "esc"
+    "#;
+
+    #[tokio::test(start_paused = true)]
+    async fn combo_sends_chord() {
+        let source = MemorySource::new()
+            .press(KeyCode::KEY_J)
+            .wait(20)
+            .press(KeyCode::KEY_K)
+            .release(KeyCode::KEY_J)
+            .release(KeyCode::KEY_K);
+        let sink = memory::run(pipeline(COMBO), source).await;
+        assert_eq!(sink.keys(), [(KeyCode::KEY_ESC, 1), (KeyCode::KEY_ESC, 0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn combo_term_passes() {
+        let source = MemorySource::new()
+            .press(KeyCode::KEY_J)
+            .wait(100)
+            .release(KeyCode::KEY_J);
+        let sink = memory::run(pipeline(COMBO), source).await;
+        assert_eq!(sink.timed_keys(), [(50, KeyCode::KEY_J, 1), (100, KeyCode::KEY_J, 0)]);
+    }
+}
@@ -1,18 +1,106 @@
 use evdev::{
-    uinput::VirtualDeviceBuilder,
+    uinput::VirtualDevice,
     AttributeSet,
+    EventType,
+    InputEvent,
     KeyCode, 
     RelativeAxisCode,
 };
-use crate::signals;
+use once_cell::sync::Lazy;
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    sync::atomic::{
+        AtomicBool,
+        Ordering,
+    },
+};
+use tokio::sync::Notify;
+use crate::{
+    error::{
+        self,
+        Error,
+    },
+    input::{
+        self,
+        Frame,
+        InputSink,
+    },
+    key_event_type::KeyEventType,
+    signals::{
+        self,
+        Message,
+        SourceId,
+    },
+};
 
 const TASK_ID: &str = "VIRTUAL DEVICE";
 
+/// The name of the virtual device, so tasks can avoid reading back their own events.
+pub const DEVICE_NAME: &str = "macrokey virtual device";
+
+// all possible keys using an iterator method
+static KEYS: Lazy<AttributeSet<KeyCode>> = Lazy::new(|| AttributeSet::from_iter((0..=0x2e7).map(KeyCode::new)));
+
+// copy all axis
+static RELATIVE_AXES: Lazy<AttributeSet<RelativeAxisCode>> = Lazy::new(|| AttributeSet::from_iter([
+    RelativeAxisCode::REL_X,
+    RelativeAxisCode::REL_Y,
+    RelativeAxisCode::REL_Z,
+    RelativeAxisCode::REL_RX,
+    RelativeAxisCode::REL_RY,
+    RelativeAxisCode::REL_RZ,
+    RelativeAxisCode::REL_HWHEEL,
+    RelativeAxisCode::REL_DIAL,
+    RelativeAxisCode::REL_WHEEL,
+    RelativeAxisCode::REL_MISC,
+    RelativeAxisCode::REL_RESERVED,
+    RelativeAxisCode::REL_WHEEL_HI_RES,
+    RelativeAxisCode::REL_HWHEEL_HI_RES,
+]));
+
+/// Set once the virtual device has been created.
+static CREATED: AtomicBool = AtomicBool::new(false);
+
+/// Notified by the shutdown, once the tasks stopped sending.
+static FINISH: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Returns true if the virtual device was created and is sending events.
+pub fn is_running() -> bool {
+    CREATED.load(Ordering::Relaxed)
+}
+
+/// Make the task send the events left on the channel, release the keys still pressed and return.
+pub fn finish() {
+    FINISH.notify_one();
+}
+
+/// Checks the virtual device has the key or axis of an event.
+///
+/// ## Errors
+///
+/// Returns an `Err` naming the key or axis the virtual device does not have.
+pub fn supports(ev: &InputEvent) -> Result<(), String> {
+    match ev.event_type() {
+        EventType::KEY if KEYS.contains(KeyCode::new(ev.code())) => Ok(()),
+        EventType::KEY => Err(format!("the virtual device has no key {:?}", KeyCode::new(ev.code()))),
+        EventType::RELATIVE if RELATIVE_AXES.contains(RelativeAxisCode(ev.code())) => Ok(()),
+        EventType::RELATIVE => Err(format!("the virtual device has no axis {:?}", RelativeAxisCode(ev.code()))),
+        EventType::SYNCHRONIZATION => Ok(()),
+        other => Err(format!("the virtual device has no {:?} events", other)),
+    }
+}
+
 /// Starts a virtual device and waits for events on the channel.
 ///
 /// Creates a virtual device with all possible keys, including mouse buttons and gamepad keys.
-/// Then, it waits for events on the channel and emits them to the virtual device.
-pub async fn task() {
+/// Then, it waits for events on the channel and emits them to the virtual device, until
+/// [`finish`] is called. The keys it pressed and did not release are released then, so
+/// none stay down after macrokey stops. Presses are tracked by source, see [`PressedKeys`],
+/// and the keys a source left pressed are released when it ends.
+pub async fn task() -> error::Result<()> {
     info!("{}", TASK_ID);
 
     // all possible keys, inc mouse buttons & gamepad
@@ -21,57 +109,189 @@ pub async fn task() {
   
     // TODO: relativeaxiscode , add new()
 
-    // all possible keys using an iterator method
-    let keys = AttributeSet::from_iter((0..=0x2e7).map(KeyCode::new));
-    
-    // copy all axis
-    let relative_axes = AttributeSet::from_iter([
-        RelativeAxisCode::REL_X,
-        RelativeAxisCode::REL_Y,
-        RelativeAxisCode::REL_Z,
-        RelativeAxisCode::REL_RX,
-        RelativeAxisCode::REL_RY,
-        RelativeAxisCode::REL_RZ,
-        RelativeAxisCode::REL_HWHEEL,
-        RelativeAxisCode::REL_DIAL,
-        RelativeAxisCode::REL_WHEEL,
-        RelativeAxisCode::REL_MISC,
-        RelativeAxisCode::REL_RESERVED,
-        RelativeAxisCode::REL_WHEEL_HI_RES,
-        RelativeAxisCode::REL_HWHEEL_HI_RES,
-    ]);
-
     // all possible relative axes using an inline closure
     //let relative_axes = AttributeSet::from_iter((0..=0x0c).map(|code| RelativeAxisCode(code)));
 
+    // get a lock on the receiver for the virtual device channel
+    let mut rx = signals::get_virtual_device_rx().await;
+
     // create a new device from an existing default
-    let mut device = match VirtualDeviceBuilder::new() {
-        Ok(builder) => {
-            builder
-                .name("macrokey virtual device")
-                .with_keys(&keys).unwrap()
-                .with_relative_axes(&relative_axes).unwrap()
-                //.with_absolute_axis(&absolute_axis)?
-                .build().unwrap()
-        },
+    let built = VirtualDevice::builder()
+        .and_then(|builder| builder
+            .name(DEVICE_NAME)
+            .with_keys(&KEYS)?
+            .with_relative_axes(&RELATIVE_AXES)?
+            //.with_absolute_axis(&absolute_axis)?
+            .build()
+        );
+    let mut device = match built {
+        Ok(device) => device,
         Err(e) => {
-            error!("{} Creation failed: {}", TASK_ID, e);
-            return
+            // tasks get an error when sending instead of waiting forever
+            rx.close();
+            return Err(Error::VirtualDevice(e));
         }
     };
 
 
     // display output device paths
-    for path in device.enumerate_dev_nodes_blocking().unwrap() {
-        let path = path.unwrap();
-        info!("{}: {}", TASK_ID, path.display());
+    match device.enumerate_dev_nodes_blocking() {
+        Ok(paths) => for path in paths.flatten() {
+            info!("{}: {}", TASK_ID, path.display());
+        },
+        Err(e) => error!("{} no device nodes: {}", TASK_ID, e),
     }
 
-    // get a lock on the receiver for the virtual device channel
-    let mut rx = signals::get_virtual_device_rx().await;
+    CREATED.store(true, Ordering::Relaxed);
+
+    // handle the event in a loop, an event the kernel refuses is dropped
+    let mut pressed = PressedKeys::default();
+    loop {
+        let message = tokio::select! {
+            biased;
+            message = rx.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = FINISH.notified() => break,
+        };
+        receive(&mut device, &mut pressed, message).await;
+    }
+
+    // what the tasks sent before they stopped
+    while let Ok(message) = rx.try_recv() {
+        receive(&mut device, &mut pressed, message).await;
+    }
+    release(&mut device, pressed.release_all(), "macrokey stopping").await
+}
+
+
+/// Send a frame without the events which change nothing, release the keys of a source which ended.
+async fn receive(device: &mut VirtualDevice, pressed: &mut PressedKeys, message: Message) {
+    match message {
+        Message::Frame(source, frame) => {
+            let Some(frame) = pressed.filter_frame(source, frame) else { return };
+            if let Err(e) = device.send(frame.clone()).await {
+                let events: Vec<_> = frame.iter().map(|ev| ev.destructure()).collect();
+                error!("{} can not send {:?}: {}", TASK_ID, events, e);
+            }
+        }
+        Message::Ended(source, name) => if let Err(e) = release(device, pressed.release(source), &name).await {
+            error!("{} {}", TASK_ID, e);
+        },
+    }
+}
+
+
+/// Release keys in one frame.
+async fn release(device: &mut VirtualDevice, releases: Vec<InputEvent>, reason: &str) -> error::Result<()> {
+    if releases.is_empty() { return Ok(()) }
+    let keys: Vec<KeyCode> = releases.iter().map(|ev| KeyCode::new(ev.code())).collect();
+    info!("{} release {:?}, {}", TASK_ID, keys, reason);
+    device.send(input::frame(releases)).await
+}
+
+
+/// The keys held down on the virtual device, with the sources holding each down.
+///
+/// The count of sources holding a key is its press count: the key goes down with
+/// the first source pressing it and up when the last one releases it. A press of a
+/// key the source already holds and a release of a key it does not hold change
+/// nothing, so they are dropped.
+#[derive(Debug, Default)]
+pub struct PressedKeys {
+    keys: BTreeMap<u16, BTreeSet<SourceId>>,
+}
+
+impl PressedKeys {
+    /// The event to send for an event of a source, `None` if it would change nothing.
+    pub fn filter(&mut self, source: SourceId, ev: InputEvent) -> Option<InputEvent> {
+        if ev.event_type() != EventType::KEY { return Some(ev) }
+        let sources = self.keys.entry(ev.code()).or_default();
+        let changed = match KeyEventType::from(ev.value()) {
+            KeyEventType::PRESSED => sources.insert(source) && sources.len() == 1,
+            KeyEventType::RELEASED => sources.remove(&source) && sources.is_empty(),
+            // repeats of a key the source holds
+            _ => sources.contains(&source),
+        };
+        if sources.is_empty() {
+            self.keys.remove(&ev.code());
+        }
+        changed.then_some(ev)
+    }
+
+    /// The frame without the events which would change nothing, `None` if only its SYN_REPORT is left.
+    pub fn filter_frame(&mut self, source: SourceId, frame: Frame) -> Option<Frame> {
+        let frame: Frame = frame.into_iter().filter_map(|ev| self.filter(source, ev)).collect();
+        frame.iter().any(|ev| ev.event_type() != EventType::SYNCHRONIZATION).then_some(frame)
+    }
+
+    /// Releases for the keys the source held and no other source holds, e.g. its device was unplugged.
+    pub fn release(&mut self, source: SourceId) -> Vec<InputEvent> {
+        let mut released = Vec::new();
+        self.keys.retain(|code, sources| {
+            if sources.remove(&source) && sources.is_empty() { released.push(*code) }
+            !sources.is_empty()
+        });
+        released.into_iter().map(release_event).collect()
+    }
+
+    /// Releases for all keys held down.
+    pub fn release_all(&mut self) -> Vec<InputEvent> {
+        std::mem::take(&mut self.keys).into_keys().map(release_event).collect()
+    }
+}
+
+fn release_event(code: u16) -> InputEvent {
+    InputEvent::new(EventType::KEY.0, code, KeyEventType::RELEASED.into())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: KeyCode, value: KeyEventType) -> InputEvent {
+        InputEvent::new(EventType::KEY.0, key.code(), value.into())
+    }
+
+    fn keys(events: Vec<InputEvent>) -> Vec<KeyCode> {
+        events.iter().map(|ev| KeyCode::new(ev.code())).collect()
+    }
+
+    #[test]
+    fn redundant_events_are_dropped() {
+        let (mut pressed, source) = (PressedKeys::default(), SourceId::new());
+        let (press, release) = (key(KeyCode::KEY_A, KeyEventType::PRESSED), key(KeyCode::KEY_A, KeyEventType::RELEASED));
+        assert!(pressed.filter(source, release).is_none());
+        assert!(pressed.filter(source, press).is_some());
+        assert!(pressed.filter(source, press).is_none());
+        assert!(pressed.filter(source, key(KeyCode::KEY_A, KeyEventType::REPEAT)).is_some());
+        assert!(pressed.filter(source, release).is_some());
+        assert!(pressed.filter(source, release).is_none());
+        assert!(pressed.filter(source, key(KeyCode::KEY_A, KeyEventType::REPEAT)).is_none());
+    }
+
+    #[test]
+    fn key_is_down_while_any_source_holds_it() {
+        let (mut pressed, keyboard, action) = (PressedKeys::default(), SourceId::new(), SourceId::new());
+        let (press, release) = (key(KeyCode::KEY_LEFTCTRL, KeyEventType::PRESSED), key(KeyCode::KEY_LEFTCTRL, KeyEventType::RELEASED));
+        assert!(pressed.filter(keyboard, press).is_some());
+        assert!(pressed.filter(action, press).is_none());
+        assert!(pressed.filter(action, release).is_none());
+        assert!(pressed.filter(keyboard, release).is_some());
+    }
 
-    // handle the event in a loop
-    while let Some(event) = rx.recv().await {
-        device.emit(&[event.clone()]).unwrap();
+    #[test]
+    fn keys_of_an_ended_source_are_released() {
+        let (mut pressed, keyboard, action) = (PressedKeys::default(), SourceId::new(), SourceId::new());
+        pressed.filter(keyboard, key(KeyCode::KEY_LEFTSHIFT, KeyEventType::PRESSED));
+        pressed.filter(keyboard, key(KeyCode::KEY_A, KeyEventType::PRESSED));
+        pressed.filter(action, key(KeyCode::KEY_LEFTSHIFT, KeyEventType::PRESSED));
+        // shift stays down for the action
+        assert_eq!(keys(pressed.release(keyboard)), [KeyCode::KEY_A]);
+        assert!(pressed.release(keyboard).is_empty());
+        assert_eq!(keys(pressed.release_all()), [KeyCode::KEY_LEFTSHIFT]);
+        assert!(pressed.release_all().is_empty());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,240 @@
+use serde::{
+    de,
+    Deserialize,
+    Deserializer,
+};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Mutex,
+    },
+};
+use crate::{
+    chord::KeySequence,
+    functions,
+    input,
+    layout::Layout,
+    signals::{
+        self,
+        VirtualDeviceTx,
+    },
+    tasks::{
+        self,
+        macros,
+    },
+};
+
+/// Something a binding can do, written in the config as an inline table.
+///
+/// ## Example
+///
+/// ```toml
+/// action = { command = "wlr-which-key" }
+/// action = { keys = "ctrl+c ctrl+v" }
+/// action = { toggle_task = "auto_repeat" }
+/// action = { play_macro = { name = "farm", repeat = 3 } }
+/// action = { type = "user@example.com" }
+/// action = { type = { text = "Grüße", layout = "de" } }
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Action {
+    /// Run a shell command.
+    Command(String),
+    /// Tap a sequence of chords on the virtual device.
+    Keys(KeySequence),
+    /// Stop or start the named task.
+    ToggleTask(String),
+    /// Play a recorded macro on the virtual device.
+    PlayMacro(macros::Playback),
+    /// Type text on the virtual device.
+    Type(Text),
+}
+
+/// Set by `macrokey replay`, actions are logged instead of run.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Log actions instead of running them, and don't save recorded macros.
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+/// Returns true if actions are only logged.
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// The actions of the config which can be run by name.
+static NAMED: Lazy<Mutex<HashMap<String, Action>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Replace the actions which can be run by name.
+pub fn set_named(actions: HashMap<String, Action>) {
+    *NAMED.lock().unwrap() = actions;
+}
+
+/// The action with this name in the config.
+pub fn named(name: &str) -> Option<Action> {
+    NAMED.lock().unwrap().get(name).cloned()
+}
+
+
+impl Action {
+    /// Run the action in the background.
+    pub fn spawn(&self) {
+        if is_dry_run() {
+            info!("Action (dry run): {:?}", self);
+            return;
+        }
+        let action = self.clone();
+        tokio::spawn(async move { action.run().await });
+    }
+
+    /// Run the action to completion, its keys are sent to the virtual device as a source of their own.
+    pub async fn run(&self) {
+        self.run_with(None).await
+    }
+
+    /// Run the action to completion, sending its keys to `tx`.
+    #[cfg(test)]
+    pub async fn run_on(&self, tx: VirtualDeviceTx) {
+        self.run_with(Some(tx)).await
+    }
+
+    async fn run_with(&self, tx: Option<VirtualDeviceTx>) {
+        match self {
+            Action::Command(cmd) => {
+                if let Err(e) = functions::run_command(cmd).await {
+                    error!("Command `{}` failed: {}", cmd, e);
+                }
+            }
+            Action::Keys(keys) => send(&source(tx, "action").await, keys.events()).await,
+            Action::ToggleTask(name) => {
+                if let Err(e) = tasks::toggle(name).await {
+                    error!("Can not toggle: {}", e);
+                }
+            }
+            Action::PlayMacro(playback) => {
+                let tx = source(tx, &format!("macro {}", playback.name)).await;
+                macros::play(playback, &tx).await
+            }
+            Action::Type(text) => send(&source(tx, "action").await, text.keys.events(&text.text)).await,
+        }
+    }
+}
+
+
+/// The sender for the keys of an action, a new source of the virtual device unless given.
+async fn source(tx: Option<VirtualDeviceTx>, name: &str) -> VirtualDeviceTx {
+    match tx {
+        Some(tx) => tx,
+        None => signals::get_virtual_device_tx(name).await,
+    }
+}
+
+
+async fn send(tx: &VirtualDeviceTx, events: Vec<evdev::InputEvent>) {
+    for frame in input::frames(events) {
+        if tx.send(frame).await.is_err() {
+            error!("Virtual device is not running");
+            return;
+        }
+    }
+}
+
+
+/// Runs the actions stages matched, once the events before them are sent.
+#[derive(Clone, Debug)]
+pub enum Runner {
+    /// In the background, see [`Action::spawn`].
+    Spawn,
+    /// Only logged, for tests which look at the events passed on.
+    #[cfg(test)]
+    Log,
+    /// In the background, their keys are sent to `tx` instead of the virtual device.
+    #[cfg(test)]
+    SendTo(VirtualDeviceTx),
+}
+
+impl Runner {
+    pub fn run(&self, action: Action) {
+        match self {
+            Runner::Spawn => action.spawn(),
+            #[cfg(test)]
+            Runner::Log => info!("Action (not run): {:?}", action),
+            #[cfg(test)]
+            Runner::SendTo(tx) => {
+                let tx = tx.clone();
+                tokio::spawn(async move { action.run_on(tx).await });
+            }
+        }
+    }
+}
+
+
+/// Text to type and the keyboard layout to type it with, `us` by default.
+///
+/// In the config it is either the text, or a table with `text` and `layout`,
+/// where `layout` is `us`, `de` or the path of an XKB symbols file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Text {
+    pub text: String,
+    pub layout: String,
+    /// The layout, loaded with the config so typing does not read it again.
+    keys: Layout,
+}
+
+impl Text {
+    /// The text with its layout loaded.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an `Err` if the layout can not be loaded.
+    pub fn new(text: String, layout: String) -> Result<Self, String> {
+        let keys = Layout::load(&layout)?;
+        Ok(Text { text, layout, keys })
+    }
+}
+
+/// The table form of a text as written in the config.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextFields {
+    text: String,
+    #[serde(default = "default_layout")]
+    layout: String,
+}
+
+fn default_layout() -> String {
+    "us".to_string()
+}
+
+impl<'de> Deserialize<'de> for Text {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Text;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("the text to type or a table with text and layout")
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<Self::Value, E> {
+                Text::new(text.to_string(), default_layout()).map_err(E::custom)
+            }
+
+            fn visit_map<M: de::MapAccess<'de>>(self, map: M) -> Result<Self::Value, M::Error> {
+                let fields = TextFields::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Text::new(fields.text, fields.layout).map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
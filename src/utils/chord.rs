@@ -0,0 +1,305 @@
+use evdev::{
+    EventSummary,
+    EventType,
+    InputEvent,
+    KeyCode,
+    LedCode,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+    Serializer,
+};
+use std::{
+    fmt,
+    str::FromStr,
+};
+use crate::key_event_type::KeyEventType;
+
+/// The modifier keys a chord can require.
+///
+/// Caps lock is tracked by its led, so it is a lock state rather than a held key.
+/// In the config they are written as `ctrl+alt`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Modifiers {
+    pub alt: bool,
+    pub ctrl: bool,
+    pub shift: bool,
+    pub meta: bool,
+    pub capslock: bool,
+}
+
+impl Modifiers {
+    /// Update the modifier state from a key or led event.
+    pub fn update(&mut self, ev: &InputEvent) {
+        match ev.destructure() {
+            EventSummary::Key(_, KeyCode::KEY_LEFTALT | KeyCode::KEY_RIGHTALT, value) => {
+                self.alt = value == KeyEventType::PRESSED || value == KeyEventType::REPEAT;
+            }
+            EventSummary::Key(_, KeyCode::KEY_LEFTCTRL | KeyCode::KEY_RIGHTCTRL, value) => {
+                self.ctrl = value == KeyEventType::PRESSED || value == KeyEventType::REPEAT;
+            }
+            EventSummary::Key(_, KeyCode::KEY_LEFTMETA | KeyCode::KEY_RIGHTMETA, value) => {
+                self.meta = value == KeyEventType::PRESSED || value == KeyEventType::REPEAT;
+            }
+            EventSummary::Key(_, KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT, value) => {
+                self.shift = value == KeyEventType::PRESSED || value == KeyEventType::REPEAT;
+            }
+            EventSummary::Led(_, LedCode::LED_CAPSL, value) => {
+                self.capslock = value == 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns true if the held modifiers satisfy `required`.
+    ///
+    /// Alt, ctrl, shift and meta must match exactly, so `ctrl+z` does not fire on
+    /// `ctrl+shift+z`. Caps lock is only checked when it is required.
+    pub fn matches(&self, required: &Modifiers) -> bool {
+        self.alt == required.alt
+            && self.ctrl == required.ctrl
+            && self.shift == required.shift
+            && self.meta == required.meta
+            && (!required.capslock || self.capslock)
+    }
+
+    /// Returns true if every modifier of `required` is held, others may be held too.
+    pub fn contains(&self, required: &Modifiers) -> bool {
+        (!required.alt || self.alt)
+            && (!required.ctrl || self.ctrl)
+            && (!required.shift || self.shift)
+            && (!required.meta || self.meta)
+            && (!required.capslock || self.capslock)
+    }
+
+    /// Returns true if any held key is one of the keys of `other`, caps lock is ignored.
+    pub fn intersects(&self, other: &Modifiers) -> bool {
+        (self.alt && other.alt)
+            || (self.ctrl && other.ctrl)
+            || (self.shift && other.shift)
+            || (self.meta && other.meta)
+    }
+
+    /// Set the modifier with this name, returns false if it is not a modifier name.
+    fn set(&mut self, name: &str) -> bool {
+        match name.to_lowercase().as_str() {
+            "alt" => self.alt = true,
+            "ctrl" | "control" => self.ctrl = true,
+            "shift" => self.shift = true,
+            "meta" | "super" | "win" => self.meta = true,
+            "capslock" | "caps" => self.capslock = true,
+            _ => return false,
+        }
+        true
+    }
+
+    /// The keys to hold down to send these modifiers, caps lock is never sent.
+    pub fn keys(&self) -> Vec<KeyCode> {
+        let mut keys = Vec::new();
+        if self.ctrl { keys.push(KeyCode::KEY_LEFTCTRL) }
+        if self.alt { keys.push(KeyCode::KEY_LEFTALT) }
+        if self.shift { keys.push(KeyCode::KEY_LEFTSHIFT) }
+        if self.meta { keys.push(KeyCode::KEY_LEFTMETA) }
+        keys
+    }
+}
+
+
+/// Returns true for the modifier keys and caps lock, which are held with other keys rather than pressed alone.
+pub fn is_modifier(key: KeyCode) -> bool {
+    matches!(key,
+        KeyCode::KEY_LEFTALT | KeyCode::KEY_RIGHTALT
+        | KeyCode::KEY_LEFTCTRL | KeyCode::KEY_RIGHTCTRL
+        | KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT
+        | KeyCode::KEY_LEFTMETA | KeyCode::KEY_RIGHTMETA
+        | KeyCode::KEY_CAPSLOCK
+    )
+}
+
+
+impl FromStr for Modifiers {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = Modifiers::default();
+        for part in s.split('+') {
+            if !modifiers.set(part.trim()) {
+                return Err(format!("unknown modifier `{}` in `{}`", part.trim(), s));
+            }
+        }
+        Ok(modifiers)
+    }
+}
+
+impl TryFrom<String> for Modifiers {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+
+/// Parse a key name such as `z`, `F5`, `esc`, `KEY_Z` or `BTN_LEFT`.
+///
+/// ## Errors
+///
+/// Returns an `Err` describing the name if no key has that name.
+pub fn parse_key(name: &str) -> Result<KeyCode, String> {
+    let upper = name.trim().to_uppercase();
+    let upper = match upper.as_str() {
+        "ESCAPE" => "ESC".to_string(),
+        "RETURN" => "ENTER".to_string(),
+        "DEL" => "DELETE".to_string(),
+        "INS" => "INSERT".to_string(),
+        "PGUP" => "PAGEUP".to_string(),
+        "PGDN" => "PAGEDOWN".to_string(),
+        "ARROWUP" => "UP".to_string(),
+        "ARROWDOWN" => "DOWN".to_string(),
+        "ARROWLEFT" => "LEFT".to_string(),
+        "ARROWRIGHT" => "RIGHT".to_string(),
+        _ => upper,
+    };
+    [upper.clone(), format!("KEY_{}", upper), format!("BTN_{}", upper)]
+        .iter()
+        .find_map(|name| KeyCode::from_str(name).ok())
+        .ok_or_else(|| format!("unknown key `{}`", name.trim()))
+}
+
+
+/// A key name from the config, parsed with [`parse_key`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Key(pub KeyCode);
+
+impl TryFrom<String> for Key {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        parse_key(&s).map(Key)
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_key(s).map(Key)
+    }
+}
+
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:?}", self.0))
+    }
+}
+
+
+/// A key with the modifiers that must be held with it, written as `ctrl+shift+z`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Chord {
+    pub modifiers: Modifiers,
+    pub key: KeyCode,
+}
+
+impl Chord {
+    /// Returns true if this is a press of the chord's key with exactly its modifiers held.
+    pub fn is_pressed(&self, modifiers: &Modifiers, ev: &InputEvent) -> bool {
+        ev.event_type() == EventType::KEY
+            && ev.value() == KeyEventType::PRESSED
+            && ev.code() == self.key.code()
+            && modifiers.matches(&self.modifiers)
+    }
+
+    /// The events to follow a source key going down, repeating or up with this chord.
+    ///
+    /// Modifiers are pressed before the key and released after it.
+    pub fn follow(&self, value: i32) -> Vec<InputEvent> {
+        let modifiers = self.modifiers.keys();
+        let key = InputEvent::new_now(EventType::KEY.0, self.key.code(), value);
+        let modifier = |code: &KeyCode| InputEvent::new_now(EventType::KEY.0, code.code(), value);
+        match KeyEventType::from(value) {
+            KeyEventType::PRESSED => modifiers.iter().map(modifier).chain([key]).collect(),
+            KeyEventType::RELEASED => [key].into_iter().chain(modifiers.iter().rev().map(modifier)).collect(),
+            _ => vec![key],
+        }
+    }
+
+    /// The events to tap this chord: press the modifiers, tap the key, release the modifiers.
+    pub fn events(&self) -> Vec<InputEvent> {
+        let mut events = self.follow(KeyEventType::PRESSED.into());
+        events.extend(self.follow(KeyEventType::RELEASED.into()));
+        events
+    }
+}
+
+impl FromStr for Chord {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut modifiers = Modifiers::default();
+        let mut key = None;
+        for part in s.split('+') {
+            let part = part.trim();
+            if part.is_empty() {
+                return Err(format!("empty key in chord `{}`", s));
+            }
+            if modifiers.set(part) { continue }
+            if key.is_some() {
+                return Err(format!("more than one key in chord `{}`", s));
+            }
+            key = Some(parse_key(part).map_err(|e| format!("{} in chord `{}`", e, s))?);
+        }
+        match key {
+            Some(key) => Ok(Chord { modifiers, key }),
+            None => Err(format!("no key in chord `{}`", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Chord {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.ctrl { write!(f, "ctrl+")? }
+        if self.modifiers.alt { write!(f, "alt+")? }
+        if self.modifiers.shift { write!(f, "shift+")? }
+        if self.modifiers.meta { write!(f, "meta+")? }
+        if self.modifiers.capslock { write!(f, "capslock+")? }
+        write!(f, "{:?}", self.key)
+    }
+}
+
+
+/// A whitespace separated list of chords, tapped one after another, e.g. `ctrl+c ctrl+v`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeySequence(pub Vec<Chord>);
+
+impl KeySequence {
+    /// The events to tap every chord in order.
+    pub fn events(&self) -> Vec<InputEvent> {
+        self.0.iter().flat_map(|chord| chord.events()).collect()
+    }
+}
+
+impl TryFrom<String> for KeySequence {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let chords = s.split_whitespace().map(str::parse).collect::<Result<Vec<Chord>, _>>()?;
+        if chords.is_empty() {
+            return Err("empty key sequence".to_string());
+        }
+        Ok(KeySequence(chords))
+    }
+}
@@ -0,0 +1,274 @@
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    env,
+    fmt,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+use crate::action::Action;
+use futures_util::StreamExt;
+use inotify::{
+    Inotify,
+    WatchMask,
+};
+use tokio::{
+    signal::unix::{
+        signal,
+        SignalKind,
+    },
+    sync::Notify,
+    time::{
+        sleep,
+        Duration,
+    },
+};
+use crate::action;
+use crate::tasks::{
+    self,
+    TaskEntry,
+};
+
+/// The top level of the config file.
+///
+/// ## Example
+///
+/// ```toml
+/// [[task]]
+/// type = "hotkeys"
+///
+/// [[task]]
+/// type = "auto_repeat"
+/// name = "repeat"  # optional, defaults to the type
+/// profiles = ["gaming"]
+/// device = "keyboard"
+///
+/// [action]
+/// suspend = { command = "systemctl suspend" }
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The profile to start in, tasks with `profiles` only run in those profiles.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Tasks to run, in addition to the virtual device which always runs.
+    #[serde(default, rename = "task")]
+    pub tasks: Vec<TaskEntry>,
+    /// Actions which can be run by name from `macrokey ctl`.
+    #[serde(default, rename = "action")]
+    pub actions: HashMap<String, Action>,
+}
+
+/// The config used when no config file exists, the usb remote.
+const DEFAULT_CONFIG: &str = include_str!("../default.toml");
+
+impl Default for Config {
+    fn default() -> Self {
+        toml::from_str(DEFAULT_CONFIG).expect("default config is valid")
+    }
+}
+
+impl Config {
+    /// Checks what serde can not, task names must be unique so they can be referred to.
+    fn validate(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        for task in self.tasks.iter() {
+            if !names.insert(task.name()) {
+                return Err(format!("duplicate task name `{}`, give each task of the same type a `name`", task.name()));
+            }
+        }
+        if let Some(profile) = &self.profile {
+            if !self.tasks.iter().any(|task| task.profiles.contains(profile)) {
+                return Err(format!("no task is in profile `{}`", profile));
+            }
+        }
+        for task in self.tasks.iter() {
+            task.task.validate().map_err(|e| format!("task `{}`: {}", task.name(), e))?;
+            for action in task.task.actions() {
+                validate_action(action, &names).map_err(|e| format!("task `{}`: {}", task.name(), e))?;
+            }
+        }
+        for (name, action) in self.actions.iter() {
+            validate_action(action, &names).map_err(|e| format!("action `{}`: {}", name, e))?;
+        }
+        Ok(())
+    }
+}
+
+
+fn validate_action(action: &Action, task_names: &HashSet<&str>) -> Result<(), String> {
+    match action {
+        Action::ToggleTask(name) if !task_names.contains(name.as_str()) => {
+            Err(format!("no task named `{}` to toggle", name))
+        }
+        Action::PlayMacro(playback) => playback.validate(),
+        _ => Ok(()),
+    }
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Invalid(path, e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+
+/// The default location of the config file.
+pub fn default_path() -> PathBuf {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".config"),
+    };
+    config_dir.join("macrokey").join("config.toml")
+}
+
+
+/// Load the config from the given path, at startup.
+///
+/// A missing file is not an error, the default config is returned instead.
+///
+/// ## Errors
+///
+/// Returns an `Err` if the file can not be read or is not a valid config.
+pub fn load(path: &Path) -> Result<Config, Error> {
+    match read(path) {
+        Err(Error::Io(_, e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!("No config at {}, using defaults", path.display());
+            Ok(Config::default())
+        }
+        loaded => loaded,
+    }
+}
+
+
+/// Read the config from the given path, a missing file is an error.
+///
+/// ## Errors
+///
+/// Returns an `Err` if the file can not be read or is not a valid config.
+pub fn read(path: &Path) -> Result<Config, Error> {
+    let text = fs::read_to_string(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    let config: Config = toml::from_str(&text).map_err(|e| Error::Parse(path.to_path_buf(), e))?;
+    config.validate().map_err(|e| Error::Invalid(path.to_path_buf(), e))?;
+    Ok(config)
+}
+
+
+/// Asks [`watch`] to reload the config.
+static RELOAD: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Reload the config as if it had changed.
+pub fn reload() {
+    RELOAD.notify_one();
+}
+
+
+/// Start the tasks and actions of the config, in its profile.
+pub async fn apply(config: &Config) {
+    action::set_named(config.actions.clone());
+    tasks::apply(config.tasks.clone(), config.profile.clone()).await;
+}
+
+
+/// Reload the config when the file changes, on SIGHUP or when asked to by [`reload`].
+///
+/// Only tasks whose config changed are restarted. An invalid config is logged
+/// and ignored, so the tasks keep running with the previous config. So is a missing
+/// file, e.g. while it is moved, the defaults are only used when starting without one.
+pub async fn watch(path: PathBuf, mut current: Config) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            error!("Can not listen for SIGHUP: {}", e);
+            None
+        }
+    };
+
+    // watch the directory as editors often replace the file rather than write to it
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut changes = Inotify::init()
+        .and_then(|inotify| {
+            inotify.watches().add(&dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE)?;
+            inotify.into_event_stream([0; 1024])
+        })
+        .map_err(|e| info!("Not watching {} for changes: {}", dir.display(), e))
+        .ok();
+
+    loop {
+        tokio::select! {
+            Some(_) = async { hangup.as_mut()?.recv().await } => {
+                info!("SIGHUP, reload config");
+            }
+            _ = RELOAD.notified() => {
+                info!("Reload config requested");
+            }
+            Some(event) = async { changes.as_mut()?.next().await } => {
+                match event {
+                    Ok(event) if event.name == file_name => {}
+                    Ok(_) => continue,
+                    Err(e) => {
+                        error!("Stopped watching {}: {}", dir.display(), e);
+                        changes = None;
+                        continue;
+                    }
+                }
+                // let the editor finish writing
+                sleep(Duration::from_millis(100)).await;
+            }
+            else => return,
+        }
+
+        match read(&path) {
+            Ok(config) if config == current => {}
+            Ok(config) => {
+                info!("Reload config {}", path.display());
+                // a profile switched to with `macrokey ctl` stays active, unless the file changes it
+                let profile = match config.profile != current.profile {
+                    true => config.profile.clone(),
+                    false => tasks::profile().await,
+                };
+                action::set_named(config.actions.clone());
+                tasks::apply(config.tasks.clone(), profile).await;
+                current = config;
+            }
+            Err(e) => error!("Config error, keeping the previous config: {}", e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_file_is_only_defaulted_at_startup() {
+        let path = env::temp_dir().join(format!("macrokey-missing-{}.toml", std::process::id()));
+        assert_eq!(load(&path).unwrap(), Config::default());
+        assert!(matches!(read(&path), Err(Error::Io(_, e)) if e.kind() == std::io::ErrorKind::NotFound));
+    }
+}
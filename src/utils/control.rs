@@ -0,0 +1,489 @@
+use clap::Subcommand;
+use evdev::{
+    EventType,
+    InputEvent,
+    KeyCode,
+    RelativeAxisCode,
+};
+use once_cell::sync::Lazy;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::Value;
+use std::{
+    env,
+    fs,
+    os::unix::fs::{
+        DirBuilderExt,
+        MetadataExt,
+        PermissionsExt,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+    sync::Mutex,
+};
+use tokio::{
+    io::{
+        AsyncBufReadExt,
+        AsyncWriteExt,
+        BufReader,
+    },
+    net::{
+        UnixListener,
+        UnixStream,
+    },
+};
+use crate::{
+    action,
+    chord::{
+        self,
+        Key,
+        KeySequence,
+    },
+    config,
+    devices,
+    input,
+    key_event_type::KeyEventType,
+    layout::Layout,
+    signals,
+    tasks::{
+        self,
+        auto_repeat::{
+            self,
+            Control,
+        },
+        virtual_device,
+    },
+};
+
+/// A request to the running daemon, one json object per line.
+///
+/// ## Example
+///
+/// ```json
+/// {"cmd": "list_tasks"}
+/// {"cmd": "start_repeat", "key": "F5", "task": "repeat"}
+/// {"cmd": "switch_profile", "name": "gaming"}
+/// ```
+#[derive(Clone, Debug, Subcommand, Serialize, Deserialize)]
+// the variants without fields have braces, serde ignores unknown fields of unit variants
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Request {
+    /// List the tasks of the config and if they are running.
+    ListTasks {},
+    /// List the devices each task is using.
+    ListDevices {},
+    /// List the keys being repeated by auto_repeat tasks.
+    ListRepeats {},
+    /// Start repeating a key.
+    StartRepeat {
+        key: Key,
+        /// The auto_repeat task, needed if more than one is running.
+        #[arg(long)]
+        #[serde(default)]
+        task: Option<String>,
+    },
+    /// Stop repeating a key.
+    StopRepeat {
+        key: Key,
+        /// The auto_repeat task, needed if more than one is running.
+        #[arg(long)]
+        #[serde(default)]
+        task: Option<String>,
+    },
+    /// Stop all repeats.
+    StopAllRepeats {
+        /// Only the repeats of this auto_repeat task.
+        #[arg(long)]
+        #[serde(default)]
+        task: Option<String>,
+    },
+    /// Pause all repeats.
+    PauseRepeats {
+        /// Only the repeats of this auto_repeat task.
+        #[arg(long)]
+        #[serde(default)]
+        task: Option<String>,
+    },
+    /// Resume paused repeats.
+    ResumeRepeats {
+        /// Only the repeats of this auto_repeat task.
+        #[arg(long)]
+        #[serde(default)]
+        task: Option<String>,
+    },
+    /// Run the tasks of a profile, or only the tasks without profiles if no name is given.
+    SwitchProfile {
+        #[serde(default)]
+        name: Option<String>,
+    },
+    /// Run an action from the `[action]` table of the config.
+    RunAction {
+        name: String,
+    },
+    /// Stop a running task, or start it again.
+    ToggleTask {
+        name: String,
+    },
+    /// Reload the config file.
+    Reload {},
+    #[command(flatten)]
+    #[serde(untagged)]
+    Inject(Inject),
+}
+
+impl Request {
+    /// Handle the request in the daemon, returns the data to send back.
+    async fn run(self) -> Result<Value, String> {
+        let data = match self {
+            Request::ListTasks {} => json(tasks::list().await),
+            Request::ListDevices {} => json(devices::matched()),
+            Request::ListRepeats {} => json(auto_repeat::repeats().await),
+            Request::StartRepeat { key, task } => {
+                auto_repeat::control(task.as_deref(), Control::Start(key)).await?;
+                Value::Null
+            }
+            Request::StopRepeat { key, task } => {
+                auto_repeat::control(task.as_deref(), Control::Stop(key)).await?;
+                Value::Null
+            }
+            Request::StopAllRepeats { task } => {
+                auto_repeat::control(task.as_deref(), Control::StopAll).await?;
+                Value::Null
+            }
+            Request::PauseRepeats { task } => {
+                auto_repeat::control(task.as_deref(), Control::Pause).await?;
+                Value::Null
+            }
+            Request::ResumeRepeats { task } => {
+                auto_repeat::control(task.as_deref(), Control::Resume).await?;
+                Value::Null
+            }
+            Request::SwitchProfile { name } => {
+                tasks::switch_profile(name).await?;
+                Value::Null
+            }
+            Request::RunAction { name } => {
+                let named = action::named(&name).ok_or_else(|| format!("no action named `{}`", name))?;
+                info!("Control: run action {}", name);
+                named.spawn();
+                Value::Null
+            }
+            Request::ToggleTask { name } => {
+                tasks::toggle(&name).await?;
+                Value::Null
+            }
+            Request::Reload {} => {
+                config::reload();
+                Value::Null
+            }
+            Request::Inject(inject) => {
+                inject.run().await?;
+                Value::Null
+            }
+        };
+        Ok(data)
+    }
+}
+
+
+/// Events for the virtual device of the running daemon, also available as
+/// `macrokey key`, `macrokey type`, `macrokey mousemove` and `macrokey click`.
+///
+/// ## Example
+///
+/// ```json
+/// {"cmd": "key", "keys": ["ctrl+a", "ctrl+c"]}
+/// {"cmd": "type", "text": "Grüße", "layout": "de"}
+/// {"cmd": "mousemove", "x": 10, "y": -5}
+/// {"cmd": "click", "button": "left"}
+/// ```
+#[derive(Clone, Debug, Subcommand, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Inject {
+    /// Tap keys on the virtual device, e.g. `ctrl+c` or `ctrl+a ctrl+c`.
+    Key {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// Type text on the virtual device.
+    Type {
+        text: String,
+        /// Keyboard layout, `us`, `de` or the path of an XKB symbols file.
+        #[arg(long)]
+        #[serde(default)]
+        layout: Option<String>,
+    },
+    /// Move the mouse of the virtual device, relative to where it is.
+    #[command(allow_negative_numbers = true)]
+    Mousemove {
+        x: i32,
+        y: i32,
+    },
+    /// Click a mouse button of the virtual device: left, right, middle, side, extra or a key name.
+    Click {
+        button: String,
+    },
+}
+
+impl Inject {
+    /// The events to send, checked against the keys and axes of the virtual device.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an `Err` if a key, button or layout is unknown, or the virtual device does not have it.
+    fn events(&self) -> Result<Vec<InputEvent>, String> {
+        let events = match self {
+            Inject::Key { keys } => KeySequence::try_from(keys.join(" "))?.events(),
+            Inject::Type { text, layout } => Layout::load(layout.as_deref().unwrap_or("us"))?.events(text),
+            Inject::Mousemove { x, y } => vec![
+                InputEvent::new_now(EventType::RELATIVE.0, RelativeAxisCode::REL_X.0, *x),
+                InputEvent::new_now(EventType::RELATIVE.0, RelativeAxisCode::REL_Y.0, *y),
+            ],
+            Inject::Click { button } => {
+                let button = parse_button(button)?;
+                vec![
+                    InputEvent::new_now(EventType::KEY.0, button.code(), KeyEventType::PRESSED.into()),
+                    InputEvent::new_now(EventType::KEY.0, button.code(), KeyEventType::RELEASED.into()),
+                ]
+            }
+        };
+        events.iter().try_for_each(virtual_device::supports)?;
+        Ok(events)
+    }
+
+    /// Send the events to the virtual device.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an `Err` if the events are not valid, or the virtual device is not running.
+    async fn run(&self) -> Result<(), String> {
+        let events = self.events()?;
+        if !virtual_device::is_running() {
+            return Err("the virtual device is not running".to_string());
+        }
+        let tx = signals::get_virtual_device_tx("control socket").await;
+        for frame in input::frames(events) {
+            tx.send(frame).await.map_err(|_| "the virtual device is not running".to_string())?;
+        }
+        Ok(())
+    }
+}
+
+
+/// A mouse button such as `left`, or any key name, see [`chord::parse_key`].
+fn parse_button(name: &str) -> Result<KeyCode, String> {
+    let upper = name.trim().to_uppercase();
+    KeyCode::from_str(&format!("BTN_{}", upper)).or_else(|_| chord::parse_key(name))
+}
+
+
+fn json<T: Serialize>(value: T) -> Value {
+    serde_json::to_value(value).unwrap_or_default()
+}
+
+
+/// The answer to a request, `{"ok": true, "data": ...}` or `{"ok": false, "error": "..."}`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub data: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<Result<Value, String>> for Response {
+    fn from(result: Result<Value, String>) -> Self {
+        match result {
+            Ok(data) => Response { ok: true, data, error: None },
+            Err(e) => Response { ok: false, data: Value::Null, error: Some(e) },
+        }
+    }
+}
+
+
+/// The default socket, `$XDG_RUNTIME_DIR/macrokey.sock` or `/tmp/macrokey-<uid>/macrokey.sock`.
+pub fn socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("macrokey.sock"),
+        _ => PathBuf::from(format!("/tmp/macrokey-{}", nix::unistd::getuid())).join("macrokey.sock"),
+    }
+}
+
+
+/// Make sure only we can reach the socket before it is created, its directory
+/// is created as 0700 if missing, and has to be ours and closed to others.
+fn private_dir(path: &Path) -> Result<(), String> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if !dir.exists() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+    let metadata = fs::metadata(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    if metadata.uid() != nix::unistd::getuid().as_raw() || metadata.mode() & 0o077 != 0 {
+        return Err(format!("{} has to be a directory only we can use (0700)", dir.display()));
+    }
+    Ok(())
+}
+
+
+/// The socket created by [`serve`], removed by [`remove_socket`].
+static SOCKET: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
+
+
+/// Answer requests on the control socket, until the daemon exits.
+///
+/// A socket left behind by a previous run is replaced. Only the owner can connect,
+/// the socket is made in a directory only the owner can use, and is 0600.
+pub async fn serve(path: PathBuf) {
+    if UnixStream::connect(&path).await.is_ok() {
+        error!("Control socket {} is in use, is macrokey already running?", path.display());
+        return;
+    }
+    if let Err(e) = private_dir(&path) {
+        error!("Can not create control socket {}: {}", path.display(), e);
+        return;
+    }
+    let _ = fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Can not create control socket {}: {}", path.display(), e);
+            return;
+        }
+    };
+    if let Err(e) = fs::set_permissions(&path, fs::Permissions::from_mode(0o600)) {
+        error!("Can not set the permissions of control socket {}: {}", path.display(), e);
+    }
+    *SOCKET.lock().unwrap() = Some(path.clone());
+    info!("Control socket {}", path.display());
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => { tokio::spawn(handle(stream)); }
+            Err(e) => error!("Control socket: {}", e),
+        }
+    }
+}
+
+
+/// Remove the socket created by [`serve`], when the daemon exits.
+pub fn remove_socket() {
+    if let Some(path) = SOCKET.lock().unwrap().take() {
+        if let Err(e) = fs::remove_file(&path) {
+            error!("Can not remove control socket {}: {}", path.display(), e);
+        }
+    }
+}
+
+
+/// A line of a connection, the error is sent back as the answer.
+fn parse(line: &str) -> Result<Request, String> {
+    serde_json::from_str(line).map_err(|e| format!("bad request: {}", e))
+}
+
+
+/// Answer each line of a connection, until it is closed.
+async fn handle(stream: UnixStream) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() { continue }
+        let response = match parse(&line) {
+            Ok(request) => Response::from(request.run().await),
+            Err(e) => Response::from(Err(e)),
+        };
+        let mut text = serde_json::to_string(&response).unwrap_or_default();
+        text.push('\n');
+        if write.write_all(text.as_bytes()).await.is_err() { break }
+    }
+}
+
+
+/// Send a request to the running daemon and wait for the answer.
+///
+/// ## Errors
+///
+/// Returns an `Err` if the daemon can not be reached, or the request failed.
+pub async fn send(path: &Path, request: &Request) -> Result<Value, String> {
+    let stream = UnixStream::connect(path).await
+        .map_err(|e| format!("can not connect to {}, is macrokey running? {}", path.display(), e))?;
+    let (read, mut write) = stream.into_split();
+    let mut text = serde_json::to_string(request).map_err(|e| e.to_string())?;
+    text.push('\n');
+    write.write_all(text.as_bytes()).await.map_err(|e| e.to_string())?;
+
+    let line = BufReader::new(read).lines().next_line().await
+        .map_err(|e| e.to_string())?
+        .ok_or("no answer from macrokey")?;
+    let response: Response = serde_json::from_str(&line).map_err(|e| format!("bad answer: {}", e))?;
+    match response.error {
+        Some(e) if !response.ok => Err(e),
+        _ => Ok(response.data),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn requests_round_trip() {
+        let lines = [
+            json!({"cmd": "list_tasks"}),
+            json!({"cmd": "list_devices"}),
+            json!({"cmd": "list_repeats"}),
+            json!({"cmd": "start_repeat", "key": "KEY_F5", "task": "repeat"}),
+            json!({"cmd": "stop_repeat", "key": "KEY_F5", "task": null}),
+            json!({"cmd": "stop_all_repeats", "task": "repeat"}),
+            json!({"cmd": "pause_repeats", "task": null}),
+            json!({"cmd": "resume_repeats", "task": null}),
+            json!({"cmd": "switch_profile", "name": "gaming"}),
+            json!({"cmd": "run_action", "name": "greet"}),
+            json!({"cmd": "toggle_task", "name": "repeat"}),
+            json!({"cmd": "reload"}),
+            json!({"cmd": "key", "keys": ["ctrl+a", "ctrl+c"]}),
+            json!({"cmd": "type", "text": "Grüße", "layout": "de"}),
+            json!({"cmd": "mousemove", "x": 10, "y": -5}),
+            json!({"cmd": "click", "button": "left"}),
+        ];
+        for line in lines {
+            let request = parse(&line.to_string()).unwrap();
+            assert_eq!(serde_json::to_value(&request).unwrap(), line);
+        }
+    }
+
+    #[test]
+    fn inject_is_parsed_through_the_untagged_variant() {
+        let request = parse(r#"{"cmd": "mousemove", "x": 10, "y": -5}"#).unwrap();
+        assert!(matches!(request, Request::Inject(Inject::Mousemove { x: 10, y: -5 })));
+        let request = parse(r#"{"cmd": "type", "text": "hi"}"#).unwrap();
+        assert!(matches!(request, Request::Inject(Inject::Type { layout: None, .. })));
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(parse(r#"{"cmd": "list_tasks", "verbose": true}"#).is_err());
+        assert!(parse(r#"{"cmd": "toggle_task", "name": "repeat", "task": "x"}"#).is_err());
+        assert!(parse(r#"{"cmd": "click", "button": "left", "count": 2}"#).is_err());
+        assert!(parse(r#"{"cmd": "explode"}"#).is_err());
+    }
+
+    #[test]
+    fn bad_line_is_answered_with_an_error() {
+        let response = Response::from(parse("list_tasks").map(|_| Value::Null));
+        let answer = serde_json::to_value(&response).unwrap();
+        assert_eq!(answer["ok"], json!(false));
+        assert!(answer["error"].as_str().unwrap().starts_with("bad request: "));
+        assert!(answer.get("data").is_none());
+
+        let ok = serde_json::to_value(Response::from(Ok(json!(["repeat"])))).unwrap();
+        assert_eq!(ok, json!({"ok": true, "data": ["repeat"]}));
+    }
+}
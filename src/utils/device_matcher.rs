@@ -0,0 +1,261 @@
+use evdev::{
+    BusType,
+    Device,
+    EventType,
+    InputId,
+    PropType,
+};
+use regex::Regex;
+use serde::{
+    de,
+    Deserialize,
+    Deserializer,
+};
+use std::{
+    fmt,
+    str::FromStr,
+};
+use crate::tasks::virtual_device;
+
+/// Picks devices by name, id, physical path and capabilities.
+///
+/// Every field that is given must match. In the config it is either a regex of
+/// the device name, or a table of fields.
+///
+/// ## Example
+///
+/// ```toml
+/// device = "keyboard"
+/// device = { name = "AT Translated Set 2 keyboard" }
+/// device = { name_regex = "^Usb Audio Device", vendor = 0x1234, bustype = "usb", events = ["KEY"] }
+/// ```
+#[derive(Clone, Debug)]
+pub struct DeviceMatcher {
+    /// Exact device name.
+    pub name: Option<String>,
+    /// Regex of the device name.
+    pub name_regex: Option<Regex>,
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    pub bustype: Option<BusType>,
+    /// Exact physical path, e.g. `usb-0000:00:14.0-1/input0`.
+    pub phys: Option<String>,
+    /// Exact unique id, often a serial number.
+    pub uniq: Option<String>,
+    /// Properties the device must have, e.g. `POINTER`.
+    pub properties: Vec<PropType>,
+    /// Event types the device must support, e.g. `KEY` or `RELATIVE`.
+    pub events: Vec<EventType>,
+    /// Never match our own virtual device, so tasks don't read back what they sent.
+    pub exclude_virtual: bool,
+}
+
+impl Default for DeviceMatcher {
+    /// Matches every device except the virtual device.
+    fn default() -> Self {
+        DeviceMatcher {
+            name: None,
+            name_regex: None,
+            vendor: None,
+            product: None,
+            bustype: None,
+            phys: None,
+            uniq: None,
+            properties: Vec::new(),
+            events: Vec::new(),
+            exclude_virtual: true,
+        }
+    }
+}
+
+impl DeviceMatcher {
+    /// Matches devices with exactly this name.
+    pub fn name(name: &str) -> Self {
+        DeviceMatcher { name: Some(name.to_string()), ..Default::default() }
+    }
+
+    /// Matches devices with a name matching the regex.
+    pub fn name_regex(regex: &str) -> Result<Self, regex::Error> {
+        Ok(DeviceMatcher { name_regex: Some(Regex::new(regex)?), ..Default::default() })
+    }
+
+    /// Returns true if every given field matches the device.
+    pub fn matches(&self, device: &impl DeviceInfo) -> bool {
+        let name = device.name().unwrap_or("");
+        let id = device.input_id();
+        !(self.exclude_virtual && name == virtual_device::DEVICE_NAME)
+            && self.name.as_ref().is_none_or(|n| n == name)
+            && self.name_regex.as_ref().is_none_or(|r| r.is_match(name))
+            && self.vendor.is_none_or(|v| v == id.vendor())
+            && self.product.is_none_or(|p| p == id.product())
+            && self.bustype.is_none_or(|b| b == id.bus_type())
+            && self.phys.as_deref().is_none_or(|p| Some(p) == device.physical_path())
+            && self.uniq.as_deref().is_none_or(|u| Some(u) == device.unique_name())
+            && self.properties.iter().all(|p| device.has_property(*p))
+            && self.events.iter().all(|e| device.has_events(*e))
+    }
+}
+
+
+/// What a matcher looks at, of a device or of a recorded one.
+pub trait DeviceInfo {
+    fn name(&self) -> Option<&str>;
+    fn input_id(&self) -> InputId;
+    fn physical_path(&self) -> Option<&str>;
+    fn unique_name(&self) -> Option<&str>;
+    fn has_property(&self, property: PropType) -> bool;
+    /// Returns true if the device sends events of the type.
+    fn has_events(&self, event_type: EventType) -> bool;
+}
+
+impl DeviceInfo for Device {
+    fn name(&self) -> Option<&str> {
+        Device::name(self)
+    }
+
+    fn input_id(&self) -> InputId {
+        Device::input_id(self)
+    }
+
+    fn physical_path(&self) -> Option<&str> {
+        Device::physical_path(self)
+    }
+
+    fn unique_name(&self) -> Option<&str> {
+        Device::unique_name(self)
+    }
+
+    fn has_property(&self, property: PropType) -> bool {
+        self.properties().contains(property)
+    }
+
+    fn has_events(&self, event_type: EventType) -> bool {
+        self.supported_events().contains(event_type)
+    }
+}
+
+impl PartialEq for DeviceMatcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.name_regex.as_ref().map(Regex::as_str) == other.name_regex.as_ref().map(Regex::as_str)
+            && self.vendor == other.vendor
+            && self.product == other.product
+            && self.bustype == other.bustype
+            && self.phys == other.phys
+            && self.uniq == other.uniq
+            && self.properties == other.properties
+            && self.events == other.events
+            && self.exclude_virtual == other.exclude_virtual
+    }
+}
+
+
+/// The table form of a matcher as written in the config, before names are parsed.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Fields {
+    name: Option<String>,
+    name_regex: Option<String>,
+    vendor: Option<u16>,
+    product: Option<u16>,
+    bustype: Option<String>,
+    phys: Option<String>,
+    uniq: Option<String>,
+    #[serde(default)]
+    properties: Vec<String>,
+    #[serde(default)]
+    events: Vec<String>,
+    #[serde(default = "default_true")]
+    exclude_virtual: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Parse an evdev constant name, with or without its prefix and in any case.
+fn parse_name<T: FromStr>(kind: &str, prefix: &str, name: &str) -> Result<T, String> {
+    let upper = name.to_uppercase();
+    T::from_str(&upper)
+        .or_else(|_| T::from_str(&format!("{}{}", prefix, upper)))
+        .map_err(|_| format!("unknown {} `{}`", kind, name))
+}
+
+impl TryFrom<Fields> for DeviceMatcher {
+    type Error = String;
+
+    fn try_from(fields: Fields) -> Result<Self, Self::Error> {
+        Ok(DeviceMatcher {
+            name: fields.name,
+            name_regex: fields.name_regex.as_deref().map(Regex::new).transpose().map_err(|e| e.to_string())?,
+            vendor: fields.vendor,
+            product: fields.product,
+            bustype: fields.bustype.as_deref().map(|b| parse_name("bustype", "BUS_", b)).transpose()?,
+            phys: fields.phys,
+            uniq: fields.uniq,
+            properties: fields.properties.iter().map(|p| parse_name("property", "", p)).collect::<Result<_, _>>()?,
+            events: fields.events.iter().map(|e| parse_name("event type", "", e)).collect::<Result<_, _>>()?,
+            exclude_virtual: fields.exclude_virtual,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceMatcher {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = DeviceMatcher;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a regex of the device name or a table of device fields")
+            }
+
+            fn visit_str<E: de::Error>(self, regex: &str) -> Result<Self::Value, E> {
+                DeviceMatcher::name_regex(regex).map_err(E::custom)
+            }
+
+            fn visit_map<M: de::MapAccess<'de>>(self, map: M) -> Result<Self::Value, M::Error> {
+                let fields = Fields::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                DeviceMatcher::try_from(fields).map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+impl FromStr for DeviceMatcher {
+    type Err = String;
+
+    /// A regex of the device name, or an inline table of fields as in the config,
+    /// e.g. on the command line.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.trim_start().starts_with('{') {
+            return DeviceMatcher::name_regex(s).map_err(|e| e.to_string());
+        }
+        #[derive(Deserialize)]
+        struct Table {
+            device: DeviceMatcher,
+        }
+        let table: Table = toml::from_str(&format!("device = {}", s)).map_err(|e| e.message().to_string())?;
+        Ok(table.device)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsed_from_a_regex_or_a_table() {
+        assert_eq!("keyboard".parse(), DeviceMatcher::name_regex("keyboard").map_err(|e| e.to_string()));
+        assert_eq!("{ vendor = 0x046d, phys = \"usb-1/input0\" }".parse(), Ok(DeviceMatcher {
+            vendor: Some(0x046d),
+            phys: Some("usb-1/input0".to_string()),
+            ..Default::default()
+        }));
+        assert!("{ vendor = 0x046d, serial = 1 }".parse::<DeviceMatcher>().is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use evdev::Device;
+use futures_util::StreamExt;
+use inotify::{
+    EventMask,
+    Inotify,
+    WatchMask,
+};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Mutex,
+    },
+};
+use tokio::{
+    sync::broadcast,
+    task::{
+        AbortHandle,
+        JoinSet,
+    },
+};
+use crate::{
+    error::{
+        self,
+        Error,
+    },
+    functions,
+    tasks,
+};
+
+const INPUT_DIR: &str = "/dev/input";
+
+#[derive(Clone, Debug)]
+enum Hotplug {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+/// Hotplug events for `/dev/input/event*`, the watcher starts with the first subscriber.
+static HOTPLUG: Lazy<broadcast::Sender<Hotplug>> = Lazy::new(|| {
+    let (tx, _) = broadcast::channel(64);
+    tokio::spawn(watch(tx.clone()));
+    tx
+});
+
+/// A task name and the names of its devices by path.
+type Run = (String, HashMap<PathBuf, String>);
+
+/// The devices of each run of [`for_each`], by run: the task name and the device names by path.
+///
+/// A restarted task has a new run, so the old run going away does not remove its devices.
+static MATCHED: Lazy<Mutex<HashMap<u64, Run>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static NEXT_RUN: AtomicU64 = AtomicU64::new(0);
+
+
+/// A device a task is running for, as listed on the control socket.
+#[derive(Clone, Debug, Serialize)]
+pub struct MatchedDevice {
+    pub task: String,
+    pub path: PathBuf,
+    pub name: String,
+}
+
+
+/// The devices the running tasks are using, sorted by task and path.
+pub fn matched() -> Vec<MatchedDevice> {
+    let matched = MATCHED.lock().unwrap();
+    let mut devices: Vec<MatchedDevice> = matched.values().flat_map(|(task, devices)| {
+        devices.iter().map(|(path, name)| MatchedDevice { task: task.clone(), path: path.clone(), name: name.clone() })
+    }).collect();
+    devices.sort_by(|a, b| (&a.task, &a.path).cmp(&(&b.task, &b.path)));
+    devices
+}
+
+
+/// The devices of one run of [`for_each`], removed from [`MATCHED`] when it is dropped.
+struct Registry(u64);
+
+impl Registry {
+    /// Register a run for the task being run, see [`tasks::TASK_NAME`].
+    fn new() -> Self {
+        let run = NEXT_RUN.fetch_add(1, Ordering::Relaxed);
+        let task = tasks::TASK_NAME.try_with(String::clone).unwrap_or_default();
+        MATCHED.lock().unwrap().insert(run, (task, HashMap::new()));
+        Registry(run)
+    }
+
+    fn add(&self, path: &Path, name: String) {
+        if let Some((_, devices)) = MATCHED.lock().unwrap().get_mut(&self.0) {
+            devices.insert(path.to_path_buf(), name);
+        }
+    }
+
+    fn remove(&self, path: &Path) {
+        if let Some((_, devices)) = MATCHED.lock().unwrap().get_mut(&self.0) {
+            devices.remove(path);
+        }
+    }
+}
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        MATCHED.lock().unwrap().remove(&self.0);
+    }
+}
+
+
+/// Watches `/dev/input` for devices being added and removed.
+///
+/// A new node is often only readable once udev has set its permissions, so a
+/// change of attributes is reported as an add as well.
+async fn watch(tx: broadcast::Sender<Hotplug>) {
+    let events = Inotify::init().and_then(|inotify| {
+        inotify.watches().add(INPUT_DIR, WatchMask::CREATE | WatchMask::ATTRIB | WatchMask::DELETE)?;
+        inotify.into_event_stream([0; 1024])
+    });
+    let mut events = match events {
+        Ok(events) => events,
+        Err(e) => {
+            error!("Not watching {} for new devices: {}", INPUT_DIR, e);
+            return;
+        }
+    };
+
+    while let Some(Ok(event)) = events.next().await {
+        let Some(name) = event.name else { continue };
+        if !name.to_string_lossy().starts_with("event") { continue }
+        let path = Path::new(INPUT_DIR).join(name);
+        let hotplug = if event.mask.contains(EventMask::DELETE) {
+            Hotplug::Removed(path)
+        } else {
+            Hotplug::Added(path)
+        };
+        // no subscribers is not an error
+        let _ = tx.send(hotplug);
+    }
+    error!("Stopped watching {} for new devices", INPUT_DIR);
+}
+
+
+/// Runs a future for each device `start` wants, for the devices present now and
+/// those plugged in later, until the returned future is dropped.
+///
+/// `start` is given each device and returns the future to run for it, or `None`
+/// to ignore the device. The future of a device is dropped when it is unplugged.
+///
+/// A device whose future returns an error is logged and dropped, the other devices
+/// carry on. It is started again when it is plugged in again.
+///
+/// ## Errors
+///
+/// Returns an `Err` if the future of a device panics, the futures of the other
+/// devices are dropped so the task can be restarted as a whole.
+pub async fn for_each<F, Fut>(mut start: F) -> error::Result<()>
+where
+    F: FnMut(Device) -> Option<Fut>,
+    Fut: Future<Output = error::Result<()>> + Send + 'static,
+{
+    // subscribe before enumerating so no device is missed
+    let mut hotplug = HOTPLUG.subscribe();
+    let mut set = JoinSet::new();
+    let mut running: HashMap<PathBuf, AbortHandle> = HashMap::new();
+    let registry = Registry::new();
+
+    for (path, device) in evdev::enumerate() {
+        let name = device.name().unwrap_or("<unnamed>").to_string();
+        if let Some(future) = start(device) {
+            registry.add(&path, name);
+            running.insert(path, set.spawn(future));
+        }
+    }
+
+    loop {
+        tokio::select! {
+            Some(joined) = set.join_next_with_id() => {
+                match joined {
+                    Ok((_, Err(e))) => error!("{}", e),
+                    Err(e) if e.is_panic() => {
+                        let path = running.iter().find(|(_, handle)| handle.id() == e.id()).map(|(path, _)| path.clone());
+                        let message = functions::panic_message(&e.into_panic());
+                        return Err(Error::Panic { path: path.unwrap_or_default(), message });
+                    }
+                    _ => {}
+                }
+                running.retain(|path, handle| {
+                    if handle.is_finished() { registry.remove(path) }
+                    !handle.is_finished()
+                });
+            }
+            hotplug_event = hotplug.recv() => match hotplug_event {
+                Ok(Hotplug::Added(path)) => {
+                    if running.get(&path).is_some_and(|handle| !handle.is_finished()) { continue }
+                    // not readable yet, wait for udev to change the permissions
+                    let Ok(device) = Device::open(&path) else { continue };
+                    let name = device.name().unwrap_or("<unnamed>").to_string();
+                    if let Some(future) = start(device) {
+                        info!("Device added: {} {}", path.display(), name);
+                        registry.add(&path, name);
+                        running.insert(path, set.spawn(future));
+                    }
+                }
+                Ok(Hotplug::Removed(path)) => {
+                    if let Some(handle) = running.remove(&path) {
+                        info!("Device removed: {}", path.display());
+                        registry.remove(&path);
+                        handle.abort();
+                    }
+                }
+                // lagged, missed devices are picked up on their next change
+                Err(_) => {}
+            }
+        }
+    }
+}
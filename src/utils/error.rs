@@ -0,0 +1,71 @@
+use std::{
+    fmt,
+    io,
+    path::PathBuf,
+};
+
+/// What can go wrong while the tasks run.
+///
+/// A device error only stops that device, the task carries on with its other
+/// devices. Any other error stops the task, and its supervisor restarts it, unless
+/// it is [fatal](Error::is_fatal).
+#[derive(Debug)]
+pub enum Error {
+    /// A device could not be grabbed or read.
+    Device {
+        name: String,
+        source: io::Error,
+    },
+    /// The future of a device panicked.
+    Panic {
+        path: PathBuf,
+        message: String,
+    },
+    /// The virtual device could not be created or written to.
+    VirtualDevice(io::Error),
+    /// The channel to the virtual device is closed, the virtual device task stopped.
+    ChannelClosed,
+    /// The config of a task can not be used.
+    Config(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// A device error, named by the device.
+    pub fn device(device: &evdev::Device, source: io::Error) -> Self {
+        Error::Device { name: device.name().unwrap_or("<unnamed>").to_string(), source }
+    }
+
+    /// Returns true if restarting the task would not help, so macrokey shuts down.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Error::ChannelClosed | Error::Config(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Device { name, source } => write!(f, "device {}: {}", name, source),
+            Error::Panic { path, message } => write!(f, "device {} panicked: {}", path.display(), message),
+            Error::VirtualDevice(source) => write!(f, "virtual device: {}", source),
+            Error::ChannelClosed => write!(f, "the virtual device is not running"),
+            Error::Config(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Device { source, .. } | Error::VirtualDevice(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for Error {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        Error::ChannelClosed
+    }
+}
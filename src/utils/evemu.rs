@@ -0,0 +1,436 @@
+use evdev::{
+    AbsoluteAxisCode,
+    BusType,
+    Device,
+    EventType,
+    InputEvent,
+    InputId,
+    KeyCode,
+    LedCode,
+    MiscCode,
+    PropType,
+    RelativeAxisCode,
+    SoundCode,
+    SwitchCode,
+    SynchronizationCode,
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{
+        self,
+        Write,
+    },
+    path::Path,
+    time::Duration,
+};
+use crate::device_matcher::{
+    DeviceInfo,
+    DeviceMatcher,
+};
+
+/// The version of the evemu format written, the oldest one with all we write.
+const VERSION: &str = "1.3";
+
+/// The description of a device as written by `evemu-describe` and `evemu-record`.
+///
+/// Only the parts a [`crate::device_matcher::DeviceMatcher`] looks at are read:
+/// the name, ids, properties and event bits. The ranges of absolute axes are written
+/// as well, so `evemu-device` can create the device again.
+///
+/// ## Example
+///
+/// ```text
+/// N: AT Translated Set 2 keyboard
+/// I: 0011 0001 0001 ab83
+/// P: 00 00 00 00 00 00 00 00
+/// B: 00 13 00 12 00 00 00 00 00
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Description {
+    pub name: String,
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+    /// Bits of the properties of the device.
+    properties: Vec<u8>,
+    /// Bits of the codes of each event type, the bits of type 0 are the event types.
+    bits: BTreeMap<u16, Vec<u8>>,
+    /// Absolute axes by code: minimum, maximum, fuzz, flat and resolution.
+    absinfo: BTreeMap<u16, [i32; 5]>,
+}
+
+impl Description {
+    pub fn new(device: &Device) -> Self {
+        let id = device.input_id();
+        let mut description = Description {
+            name: device.name().unwrap_or_default().to_string(),
+            bustype: id.bus_type().0,
+            vendor: id.vendor(),
+            product: id.product(),
+            version: id.version(),
+            ..Default::default()
+        };
+        description.properties = bits(device.properties().iter().map(|p| p.0));
+        let codes: [(EventType, Option<Vec<u16>>); 9] = [
+            (EventType::SYNCHRONIZATION, Some(device.supported_events().iter().map(|e| e.0).collect())),
+            (EventType::KEY, device.supported_keys().map(|set| set.iter().map(|c| c.0).collect())),
+            (EventType::RELATIVE, device.supported_relative_axes().map(|set| set.iter().map(|c| c.0).collect())),
+            (EventType::ABSOLUTE, device.supported_absolute_axes().map(|set| set.iter().map(|c| c.0).collect())),
+            (EventType::MISC, device.misc_properties().map(|set| set.iter().map(|c| c.0).collect())),
+            (EventType::SWITCH, device.supported_switches().map(|set| set.iter().map(|c| c.0).collect())),
+            (EventType::LED, device.supported_leds().map(|set| set.iter().map(|c| c.0).collect())),
+            (EventType::SOUND, device.supported_sounds().map(|set| set.iter().map(|c| c.0).collect())),
+            (EventType::FORCEFEEDBACK, device.supported_ff().map(|set| set.iter().map(|c| c.0).collect())),
+        ];
+        for (event_type, codes) in codes {
+            if let Some(codes) = codes {
+                description.bits.insert(event_type.0, bits(codes));
+            }
+        }
+        if let Ok(axes) = device.get_absinfo() {
+            for (code, info) in axes {
+                description.absinfo.insert(code.0, [info.minimum(), info.maximum(), info.fuzz(), info.flat(), info.resolution()]);
+            }
+        }
+        description
+    }
+
+    /// Write the description lines, with the header comments `evemu-record` writes.
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "# EVEMU {}", VERSION)?;
+        writeln!(out, "# Input device name: \"{}\"", self.name)?;
+        writeln!(out, "# Input device ID: bus {:#04x} vendor {:#06x} product {:#06x} version {:#06x}",
+            self.bustype, self.vendor, self.product, self.version)?;
+        writeln!(out, "N: {}", self.name)?;
+        writeln!(out, "I: {:04x} {:04x} {:04x} {:04x}", self.bustype, self.vendor, self.product, self.version)?;
+        write_bytes(out, "P:", &self.properties)?;
+        for (event_type, bits) in self.bits.iter() {
+            write_bytes(out, &format!("B: {:02x}", event_type), bits)?;
+        }
+        for (code, [min, max, fuzz, flat, resolution]) in self.absinfo.iter() {
+            writeln!(out, "A: {:02x} {} {} {} {} {}", code, min, max, fuzz, flat, resolution)?;
+        }
+        Ok(())
+    }
+}
+
+/// A recorded device matches as the device would, it has no physical path or unique id.
+impl DeviceInfo for Description {
+    fn name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    fn input_id(&self) -> InputId {
+        InputId::new(BusType(self.bustype), self.vendor, self.product, self.version)
+    }
+
+    fn physical_path(&self) -> Option<&str> {
+        None
+    }
+
+    fn unique_name(&self) -> Option<&str> {
+        None
+    }
+
+    fn has_property(&self, property: PropType) -> bool {
+        has_bit(&self.properties, property.0)
+    }
+
+    fn has_events(&self, event_type: EventType) -> bool {
+        self.bits.get(&EventType::SYNCHRONIZATION.0).is_some_and(|bits| has_bit(bits, event_type.0))
+    }
+}
+
+
+/// A bit mask with the given bits set, as the kernel gives them.
+fn bits(set: impl IntoIterator<Item = u16>) -> Vec<u8> {
+    let mut bits = Vec::new();
+    for bit in set {
+        let byte = bit as usize / 8;
+        if bits.len() <= byte { bits.resize(byte + 1, 0) }
+        bits[byte] |= 1 << (bit % 8);
+    }
+    bits
+}
+
+fn has_bit(bits: &[u8], bit: u16) -> bool {
+    bits.get(bit as usize / 8).is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+}
+
+/// Bytes in hex, 8 to a line as evemu writes them. At least one line is written.
+fn write_bytes(out: &mut impl Write, prefix: &str, bytes: &[u8]) -> io::Result<()> {
+    let mut chunks: Vec<&[u8]> = bytes.chunks(8).collect();
+    if chunks.is_empty() { chunks.push(&[0; 8]) }
+    for chunk in chunks {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        writeln!(out, "{} {}", prefix, hex.join(" "))?;
+    }
+    Ok(())
+}
+
+
+/// A recorded device and its events, the contents of an evemu file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Trace {
+    pub device: Description,
+    /// The events with their time since the first event.
+    pub events: Vec<(Duration, InputEvent)>,
+}
+
+impl Trace {
+    /// Read an evemu file.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an `Err` if the file can not be read or a line can not be parsed.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        text.parse().map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The events in the evemu format, a line each.
+    pub fn write_events(&self, out: &mut impl Write) -> io::Result<()> {
+        for (at, ev) in self.events.iter() {
+            writeln!(out, "{}", event_line(*at, ev))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Trace {
+    type Err = String;
+
+    /// Lines other than the name, ids, bits and events are skipped, as are comments.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut trace = Trace::default();
+        let mut first = None;
+        for (i, line) in text.lines().enumerate() {
+            let error = |e: String| format!("line {}: {}", i + 1, e);
+            let Some((kind, rest)) = line.split_once(':') else { continue };
+            // events end with a comment of their names
+            let rest = match kind {
+                "E" => rest.split('#').next().unwrap_or_default().trim(),
+                _ => rest.trim(),
+            };
+            match kind {
+                "N" => trace.device.name = rest.to_string(),
+                "I" => {
+                    let ids = rest.split_whitespace().map(hex).collect::<Result<Vec<u16>, _>>().map_err(error)?;
+                    let [bustype, vendor, product, version] = ids[..] else {
+                        return Err(error("expected 4 ids".to_string()));
+                    };
+                    (trace.device.bustype, trace.device.vendor, trace.device.product, trace.device.version) = (bustype, vendor, product, version);
+                }
+                "P" => trace.device.properties.extend(bytes(rest).map_err(error)?),
+                "B" => {
+                    let mut bytes = bytes(rest).map_err(error)?.into_iter();
+                    let event_type = bytes.next().ok_or_else(|| error("no event type".to_string()))?;
+                    trace.device.bits.entry(event_type as u16).or_default().extend(bytes);
+                }
+                "E" => {
+                    let (at, ev) = event(rest).map_err(error)?;
+                    // older versions of evemu write the time of the system, newer ones since the first event
+                    let first = *first.get_or_insert(at);
+                    trace.events.push((at.saturating_sub(first), ev));
+                }
+                _ => {}
+            }
+        }
+        Ok(trace)
+    }
+}
+
+
+fn hex(text: &str) -> Result<u16, String> {
+    u16::from_str_radix(text, 16).map_err(|_| format!("`{}` is not a hex number", text))
+}
+
+fn bytes(text: &str) -> Result<Vec<u8>, String> {
+    text.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("`{}` is not a hex byte", byte)))
+        .collect()
+}
+
+/// An event line without its `E:`, `<sec>.<usec> <type> <code> <value>`.
+fn event(text: &str) -> Result<(Duration, InputEvent), String> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let [time, event_type, code, value] = parts[..] else {
+        return Err("expected the time, type, code and value of an event".to_string());
+    };
+    let (secs, fraction) = time.split_once('.').unwrap_or((time, "0"));
+    let secs: u64 = secs.parse().map_err(|_| format!("bad time `{}`", time))?;
+    // evemu writes 6 digits, a shorter fraction is padded so `0.1` is 100ms
+    if fraction.is_empty() || fraction.len() > 6 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("bad time `{}`, up to 6 digits after the dot", time));
+    }
+    let micros: u32 = format!("{:0<6}", fraction).parse().map_err(|_| format!("bad time `{}`", time))?;
+    let value: i32 = value.parse().map_err(|_| format!("`{}` is not a number", value))?;
+    let at = Duration::new(secs, micros * 1000);
+    Ok((at, InputEvent::new(hex(event_type)?, hex(code)?, value)))
+}
+
+
+/// An event in the evemu format, with its name in a comment.
+pub fn event_line(at: Duration, ev: &InputEvent) -> String {
+    let code = match ev.event_type() {
+        EventType::SYNCHRONIZATION => format!("{:?}", SynchronizationCode(ev.code())),
+        EventType::KEY => format!("{:?}", KeyCode(ev.code())),
+        EventType::RELATIVE => format!("{:?}", RelativeAxisCode(ev.code())),
+        EventType::ABSOLUTE => format!("{:?}", AbsoluteAxisCode(ev.code())),
+        EventType::MISC => format!("{:?}", MiscCode(ev.code())),
+        EventType::SWITCH => format!("{:?}", SwitchCode(ev.code())),
+        EventType::LED => format!("{:?}", LedCode(ev.code())),
+        EventType::SOUND => format!("{:?}", SoundCode(ev.code())),
+        _ => format!("{:#06x}", ev.code()),
+    };
+    format!("E: {}.{:06} {:04x} {:04x} {:04}\t# {:?} / {} {}",
+        at.as_secs(), at.subsec_micros(), ev.event_type().0, ev.code(), ev.value(), ev.event_type(), code, ev.value())
+}
+
+
+/// Write the description of a device then its events as they come, until Ctrl+C.
+///
+/// The device is opened with [`open`] or [`find`]. The file is in the format of
+/// `evemu-record`, so it can be played back with `evemu-play`.
+///
+/// ## Errors
+///
+/// Returns an `Err` if the device or output fails.
+pub async fn record(device: Device, output: Option<&Path>) -> Result<(), String> {
+    let name = device.name().unwrap_or("<unnamed>").to_string();
+    let mut out: Box<dyn Write + Send> = match output {
+        Some(path) => Box::new(io::BufWriter::new(fs::File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?)),
+        None => Box::new(io::stdout()),
+    };
+    let failed = |e: io::Error| format!("can not write the trace: {}", e);
+    Description::new(&device).write(&mut out).map_err(failed)?;
+    writeln!(out, "################################").map_err(failed)?;
+    writeln!(out, "#      Waiting for events      #").map_err(failed)?;
+    writeln!(out, "################################").map_err(failed)?;
+    out.flush().map_err(failed)?;
+
+    info!("Recording {}, Ctrl+C to stop", name);
+    let mut events = device.into_event_stream().map_err(|e| format!("{}: {}", name, e))?;
+    let mut first = None;
+    loop {
+        let ev = tokio::select! {
+            ev = events.next_event() => ev.map_err(|e| format!("{}: {}", name, e))?,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        let first = *first.get_or_insert(ev.timestamp());
+        let at = ev.timestamp().duration_since(first).unwrap_or_default();
+        writeln!(out, "{}", event_line(at, &ev)).map_err(failed)?;
+        // a frame is complete, so a killed recording still ends with whole frames
+        if ev.event_type() == EventType::SYNCHRONIZATION {
+            out.flush().map_err(failed)?;
+        }
+    }
+    out.flush().map_err(failed)
+}
+
+
+/// The device at a /dev/input path.
+pub fn open(path: &Path) -> Result<Device, String> {
+    Device::open(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+
+/// The only device the matcher matches.
+pub fn find(matcher: &DeviceMatcher) -> Result<Device, String> {
+    let mut matched: Vec<_> = evdev::enumerate()
+        .filter(|(_, d)| matcher.matches(d))
+        .collect();
+    matched.sort_by(|a, b| a.0.cmp(&b.0));
+    match matched.len() {
+        0 => Err("no device matches".to_string()),
+        1 => Ok(matched.remove(0).1),
+        _ => {
+            let names: Vec<String> = matched.iter()
+                .map(|(path, d)| format!("{} {}", path.display(), d.name().unwrap_or("<unnamed>")))
+                .collect();
+            Err(format!("{} devices match, give the path of one or more fields:\n{}", names.len(), names.join("\n")))
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = "\
+# EVEMU 1.3
+# Input device name: \"AT Translated Set 2 keyboard\"
+N: AT Translated Set 2 keyboard
+I: 0011 0001 0001 ab83
+P: 00 00 00 00 00 00 00 00
+B: 00 13 00 12 00 00 00 00 00
+B: 01 fe ff ff ff ff ff ff ff
+B: 01 ff ff ef ff df ff ff fe
+A: 00 0 1920 0 0 0
+################################
+#      Waiting for events      #
+################################
+E: 1634.218000 0004 0004 0058	# EV_MSC / MSC_SCAN             58
+E: 1634.218000 0001 003a 0001	# EV_KEY / KEY_CAPSLOCK         1
+E: 1634.218000 0000 0000 0000	# ------------ SYN_REPORT (0) ---------- +0ms
+E: 1634.302500 0001 003a 0000	# EV_KEY / KEY_CAPSLOCK         0
+";
+
+    #[test]
+    fn parses_evemu_record_output() {
+        let trace: Trace = TRACE.parse().unwrap();
+        assert_eq!(trace.device.name, "AT Translated Set 2 keyboard");
+        assert_eq!((trace.device.bustype, trace.device.vendor, trace.device.version), (0x11, 1, 0xab83));
+        let events: Vec<(Duration, u16, u16, i32)> = trace.events.iter()
+            .map(|(at, ev)| (*at, ev.event_type().0, ev.code(), ev.value()))
+            .collect();
+        // times are from the first event
+        assert_eq!(events, [
+            (Duration::ZERO, 4, 4, 58),
+            (Duration::ZERO, 1, KeyCode::KEY_CAPSLOCK.0, 1),
+            (Duration::ZERO, 0, 0, 0),
+            (Duration::from_micros(84_500), 1, KeyCode::KEY_CAPSLOCK.0, 0),
+        ]);
+    }
+
+    #[test]
+    fn recorded_device_is_matched() {
+        let trace: Trace = TRACE.parse().unwrap();
+        let keyboard: DeviceMatcher = toml::from_str::<toml::Table>(r#"device = { name_regex = "keyboard", bustype = "i8042", events = ["KEY"] }"#)
+            .unwrap()["device"].clone().try_into().unwrap();
+        assert!(keyboard.matches(&trace.device));
+        assert!(trace.device.has_events(EventType::MISC));
+        assert!(!trace.device.has_events(EventType::RELATIVE));
+        assert!(!DeviceMatcher::name("mouse").matches(&trace.device));
+    }
+
+    #[test]
+    fn written_description_and_events_read_back() {
+        let trace: Trace = TRACE.parse().unwrap();
+        let mut text = Vec::new();
+        trace.device.write(&mut text).unwrap();
+        trace.write_events(&mut text).unwrap();
+        let read: Trace = String::from_utf8(text).unwrap().parse().unwrap();
+        assert_eq!(read.device.name, trace.device.name);
+        assert_eq!(read.device.bits, trace.device.bits);
+        assert_eq!(read.events, trace.events);
+    }
+
+    #[test]
+    fn bad_event_line() {
+        let error = "N: x\nE: 0.1 0001 zz 1\n".parse::<Trace>().unwrap_err();
+        assert_eq!(error, "line 2: `zz` is not a hex number");
+    }
+
+    #[test]
+    fn short_fraction_is_of_a_second() {
+        let trace: Trace = "N: x\nE: 0.1 0001 001e 1\nE: 0.5 0001 001e 0\n".parse().unwrap();
+        assert_eq!(trace.events[1].0, Duration::from_millis(400));
+        assert_eq!(event("0.5 0001 001e 0").unwrap().0, Duration::from_millis(500));
+        assert_eq!(event("0.1234567 0001 001e 0").unwrap_err(), "bad time `0.1234567`, up to 6 digits after the dot");
+    }
+}
@@ -42,6 +42,27 @@ pub fn list_devices() {
 }
 
 
+/// Prints all input devices available on the system to stdout as json.
+pub fn print_devices_json() {
+    let mut devices = evdev::enumerate().collect::<Vec<_>>();
+    devices.reverse();
+    let devices: Vec<_> = devices.iter().map(|(path, d)| {
+        let id = d.input_id();
+        serde_json::json!({
+            "path": path,
+            "name": d.name(),
+            "phys": d.physical_path(),
+            "uniq": d.unique_name(),
+            "bustype": format!("{:?}", id.bus_type()),
+            "vendor": format!("{:04x}", id.vendor()),
+            "product": format!("{:04x}", id.product()),
+            "properties": get_combined_properties(d),
+        })
+    }).collect();
+    println!("{}", serde_json::to_string_pretty(&devices).unwrap_or_default());
+}
+
+
 /// Combines the properties and supported events of a device into a single vector.
 ///
 /// ## Returns
@@ -56,73 +77,27 @@ pub fn get_combined_properties(device: &evdev::Device) -> Vec<String> {
 }
 
 
-/// Given a device name, returns a `Device` for that device if found.
-///
-/// ## Errors
-///
-/// Returns an `Err` if no device is found with the given name.
-pub fn get_device_by_name(name: &str) -> Result<evdev::Device, std::io::Error> {
-    let devices = evdev::enumerate().map(|t| t.1).collect::<Vec<_>>();
-    for d in devices {
-        if d.name().unwrap_or("") == name {
-            return Ok(d);
-        }
-    }
-    Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Device not found"))
-}
-
-
-/// Return a vector of devices where the device name matches the regex.
-pub fn get_devices_by_regex(regex: &str) -> Vec<evdev::Device> {
-    let devices = evdev::enumerate().map(|t| t.1).collect::<Vec<_>>();
-    let regex = regex::Regex::new(regex).unwrap();
-    let mut matching_devices = Vec::new();
-
-    for d in devices {
-        if regex.is_match(d.name().unwrap_or("")) {
-            matching_devices.push(d);
-        }
+/// Logs the supported keys of a given device.
+pub fn log_device_keys(device: &evdev::Device) {
+    match device.supported_keys() {
+        Some(keys) => info!("\nDevice: {}\nKeys: {:?}", device.name().unwrap_or(""), keys),
+        None => info!("\nDevice: {}\nKeys: none", device.name().unwrap_or("")),
     }
-
-    matching_devices
 }
 
 
-/// Return a device where the given predicate is true.
-///
-/// ## Errors
-///
-/// Returns an `Err` if no device is found where the predicate is true.
-/// 
-/// ## Example
-///
-/// ```rust
-/// let device_name = "device_name";
-/// let properties = vec!["property1", "property2"];
-/// let device = get_device_by_predicate(|d| {
-///     d.name().unwrap_or("") == device_name && 
-///     properties.iter().all(|prop| get_combined_properties(d).contains(&prop.to_string()))
-/// })?;
-/// ```
-pub fn get_device_by_predicate(predicate: impl Fn(&evdev::Device) -> bool) -> Result<evdev::Device, std::io::Error> {
-    let devices = evdev::enumerate().map(|t| t.1).collect::<Vec<_>>();
-    for d in devices {
-        if predicate(&d) {
-            return Ok(d);
-        }
+/// The message of a caught panic, as given to `panic!`.
+pub fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_string(),
     }
-    Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Device not found"))
-}
-
-
-/// Logs the supported keys of a given device.
-pub fn log_device_keys(device: &evdev::Device) {
-    let keys = device.supported_keys().unwrap();
-    info!("\nDevice: {}\nKeys: {:?}", device.name().unwrap_or(""), keys);
 }
 
 
 /// Run a shell command asynchronously.
+#[allow(clippy::needless_borrows_for_generic_args)]
 pub async fn run_command(cmd: &str) -> Result<std::process::Output, std::io::Error> {
     tokio::process::Command::new("sh")
     .args(&["-c", cmd])
@@ -0,0 +1,146 @@
+use evdev::{
+    uinput::VirtualDevice,
+    EventStream,
+    EventType,
+    InputEvent,
+    SynchronizationCode,
+};
+use std::future::Future;
+use crate::error::{
+    self,
+    Error,
+};
+
+/// Where the events a task reads come from, a device or a script in tests.
+pub trait InputSource: Send {
+    /// The next event, `None` once there are no more, e.g. the device was unplugged.
+    fn next_event(&mut self) -> impl Future<Output = Option<InputEvent>> + Send;
+}
+
+/// Where the events a task sends go, the virtual device or a list in tests.
+pub trait InputSink: Send {
+    /// Send a frame on, at once.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an `Err` if the frame can not be sent, e.g. the virtual device stopped.
+    fn send(&mut self, frame: Frame) -> impl Future<Output = error::Result<()>> + Send;
+}
+
+
+/// Events which happen at once, ending in a SYN_REPORT, e.g. the X and Y motion of a mouse.
+///
+/// Applications only act on the events of a frame once its SYN_REPORT comes.
+pub type Frame = Vec<InputEvent>;
+
+/// The events as one frame, a SYN_REPORT is added.
+pub fn frame(events: impl IntoIterator<Item = InputEvent>) -> Frame {
+    let mut frame: Frame = events.into_iter().collect();
+    frame.push(InputEvent::new(EventType::SYNCHRONIZATION.0, SynchronizationCode::SYN_REPORT.0, 0));
+    frame
+}
+
+/// Split events into frames, a frame ends where a key event does something else than the one before.
+///
+/// The presses of a chord are one frame and its releases the next, a tap is two
+/// frames so the key is seen down. SYN events are dropped, each frame gets its SYN_REPORT.
+///
+/// ## Example
+///
+/// ```ignore
+/// // [LEFTCTRL 1, C 1, SYN] [C 0, LEFTCTRL 0, SYN]
+/// let frames = input::frames("ctrl+c".parse::<Chord>()?.events());
+/// ```
+pub fn frames(events: impl IntoIterator<Item = InputEvent>) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    let mut current = Vec::new();
+    let mut key_value = None;
+    for ev in events {
+        match ev.event_type() {
+            EventType::SYNCHRONIZATION => continue,
+            EventType::KEY if key_value.is_some_and(|value| value != ev.value()) => {
+                frames.push(frame(std::mem::take(&mut current)));
+                key_value = Some(ev.value());
+            }
+            EventType::KEY => key_value = Some(ev.value()),
+            _ => {}
+        }
+        current.push(ev);
+    }
+    if !current.is_empty() {
+        frames.push(frame(current));
+    }
+    frames
+}
+
+/// Returns true for the SYN_REPORT ending a frame.
+pub fn is_syn_report(ev: &InputEvent) -> bool {
+    ev.event_type() == EventType::SYNCHRONIZATION && ev.code() == SynchronizationCode::SYN_REPORT.0
+}
+
+
+impl InputSource for EventStream {
+    async fn next_event(&mut self) -> Option<InputEvent> {
+        EventStream::next_event(self).await.ok()
+    }
+}
+
+/// The frame is emitted without its SYN_REPORT, `emit` adds one.
+impl InputSink for VirtualDevice {
+    async fn send(&mut self, mut frame: Frame) -> error::Result<()> {
+        if frame.last().is_some_and(is_syn_report) {
+            frame.pop();
+        }
+        self.emit(&frame).map_err(Error::VirtualDevice)
+    }
+}
+
+
+/// Drops every event, for devices which are not grabbed so applications already have them.
+pub struct Discard;
+
+impl InputSink for Discard {
+    async fn send(&mut self, _frame: Frame) -> error::Result<()> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use evdev::{
+        KeyCode,
+        RelativeAxisCode,
+    };
+    use super::*;
+    use crate::chord::Chord;
+
+    fn split(frames: Vec<Frame>) -> Vec<Vec<(EventType, u16, i32)>> {
+        frames.iter()
+            .map(|frame| frame.iter().map(|ev| (ev.event_type(), ev.code(), ev.value())).collect())
+            .collect()
+    }
+
+    #[test]
+    fn chord_presses_and_releases_are_frames() {
+        let (ctrl, c, syn) = (KeyCode::KEY_LEFTCTRL.0, KeyCode::KEY_C.0, (EventType::SYNCHRONIZATION, 0, 0));
+        assert_eq!(split(frames("ctrl+c".parse::<Chord>().unwrap().events())), [
+            vec![(EventType::KEY, ctrl, 1), (EventType::KEY, c, 1), syn],
+            vec![(EventType::KEY, c, 0), (EventType::KEY, ctrl, 0), syn],
+        ]);
+    }
+
+    #[test]
+    fn motion_is_one_frame() {
+        let motion = [
+            InputEvent::new(EventType::RELATIVE.0, RelativeAxisCode::REL_X.0, 3),
+            InputEvent::new(EventType::RELATIVE.0, RelativeAxisCode::REL_Y.0, -2),
+            InputEvent::new(EventType::SYNCHRONIZATION.0, SynchronizationCode::SYN_REPORT.0, 0),
+        ];
+        let frames = frames(motion);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].len(), 3);
+        assert!(is_syn_report(&frames[0][2]));
+        assert!(super::frames([]).is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug)]
 pub enum KeyEventType {
     RELEASED,
@@ -38,6 +39,13 @@ impl PartialEq<KeyEventType> for i32 {
     }
 }
 
+impl From<i32> for KeyEventType {
+    fn from(value: i32) -> Self {
+        Self::from_value(value)
+    }
+}
+
+#[allow(clippy::from_over_into)]
 impl Into<i32> for KeyEventType {
     fn into(self) -> i32 {
         self.value()
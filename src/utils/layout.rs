@@ -0,0 +1,357 @@
+use evdev::{
+    EventType,
+    InputEvent,
+    KeyCode,
+};
+use std::{
+    collections::HashMap,
+    fs,
+};
+use crate::{
+    chord::Chord,
+    key_event_type::KeyEventType,
+};
+
+const US: &str = include_str!("../layouts/us.xkb");
+const DE: &str = include_str!("../layouts/de.xkb");
+
+/// The key and modifiers that type a character.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Stroke {
+    key: KeyCode,
+    shift: bool,
+    altgr: bool,
+}
+
+impl Stroke {
+    /// Shift and AltGr are levels 2 and 3, both together is level 4.
+    fn level(&self) -> u8 {
+        1 + self.shift as u8 + 2 * self.altgr as u8
+    }
+}
+
+
+/// Maps characters to the keys that type them on a keyboard layout.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Layout {
+    strokes: HashMap<char, Stroke>,
+}
+
+impl Layout {
+    /// Load a built in layout, `us` or `de`, or an XKB symbols file by path.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an `Err` if the file can not be read or has no keys.
+    pub fn load(name: &str) -> Result<Self, String> {
+        match name {
+            "us" => Layout::parse(US),
+            "de" => Layout::parse(DE),
+            path => {
+                let text = fs::read_to_string(path).map_err(|e| format!("layout `{}`: {}", path, e))?;
+                Layout::parse(&text).map_err(|e| format!("layout `{}`: {}", path, e))
+            }
+        }
+    }
+
+    /// Parse the `key <AE01> { [ 1, exclam, onesuperior, exclamdown ] };` lines of an XKB symbols file.
+    ///
+    /// Keys and keysyms we can not type, such as dead keys, are skipped. When a
+    /// character is on more than one key the lowest level is used.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an `Err` if no key could be used.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut layout = Layout::default();
+        for line in text.lines() {
+            let Some(rest) = line.trim().strip_prefix("key <") else { continue };
+            let Some((name, rest)) = rest.split_once('>') else { continue };
+            let Some(key) = key_code(name) else { continue };
+            let Some(symbols) = rest.split_once('[').and_then(|(_, s)| s.split_once(']')).map(|(s, _)| s) else { continue };
+
+            for (level, symbol) in symbols.split(',').enumerate().take(4) {
+                let Some(c) = keysym_char(symbol.trim()) else { continue };
+                let stroke = Stroke { key, shift: level % 2 == 1, altgr: level >= 2 };
+                let lower = layout.strokes.get(&c).is_some_and(|s| s.level() <= stroke.level());
+                if !lower { layout.strokes.insert(c, stroke); }
+            }
+        }
+        if layout.strokes.is_empty() {
+            return Err("no keys found".to_string());
+        }
+        Ok(layout)
+    }
+
+    /// The events to type the text, characters not on the layout are typed as
+    /// unicode with ctrl+shift+u, the hex code and space.
+    pub fn events(&self, text: &str) -> Vec<InputEvent> {
+        text.chars().flat_map(|c| self.char_events(c)).collect()
+    }
+
+    fn char_events(&self, c: char) -> Vec<InputEvent> {
+        let stroke = match c {
+            '\n' => Some(Stroke { key: KeyCode::KEY_ENTER, shift: false, altgr: false }),
+            '\t' => Some(Stroke { key: KeyCode::KEY_TAB, shift: false, altgr: false }),
+            _ => self.strokes.get(&c).copied(),
+        };
+        if let Some(stroke) = stroke {
+            return stroke_events(&stroke);
+        }
+        let mut events = "ctrl+shift+u".parse::<Chord>().unwrap().events();
+        for digit in format!("{:x}", c as u32).chars() {
+            events.extend(tap(hex_key(digit)));
+        }
+        events.extend(tap(KeyCode::KEY_SPACE));
+        events
+    }
+}
+
+
+fn key_event(key: KeyCode, value: KeyEventType) -> InputEvent {
+    InputEvent::new_now(EventType::KEY.0, key.code(), value.into())
+}
+
+fn tap(key: KeyCode) -> Vec<InputEvent> {
+    vec![key_event(key, KeyEventType::PRESSED), key_event(key, KeyEventType::RELEASED)]
+}
+
+/// Hold shift and AltGr around a tap of the key.
+fn stroke_events(stroke: &Stroke) -> Vec<InputEvent> {
+    let mut modifiers = Vec::new();
+    if stroke.shift { modifiers.push(KeyCode::KEY_LEFTSHIFT) }
+    if stroke.altgr { modifiers.push(KeyCode::KEY_RIGHTALT) }
+
+    let mut events: Vec<InputEvent> = modifiers.iter().map(|m| key_event(*m, KeyEventType::PRESSED)).collect();
+    events.extend(tap(stroke.key));
+    events.extend(modifiers.iter().rev().map(|m| key_event(*m, KeyEventType::RELEASED)));
+    events
+}
+
+/// The key for a hex digit of a unicode code, these are the same on us and de.
+fn hex_key(digit: char) -> KeyCode {
+    match digit {
+        '0' => KeyCode::KEY_0,
+        '1' => KeyCode::KEY_1,
+        '2' => KeyCode::KEY_2,
+        '3' => KeyCode::KEY_3,
+        '4' => KeyCode::KEY_4,
+        '5' => KeyCode::KEY_5,
+        '6' => KeyCode::KEY_6,
+        '7' => KeyCode::KEY_7,
+        '8' => KeyCode::KEY_8,
+        '9' => KeyCode::KEY_9,
+        'a' => KeyCode::KEY_A,
+        'b' => KeyCode::KEY_B,
+        'c' => KeyCode::KEY_C,
+        'd' => KeyCode::KEY_D,
+        'e' => KeyCode::KEY_E,
+        _ => KeyCode::KEY_F,
+    }
+}
+
+
+/// The evdev key for an XKB key name, for the main block of a pc keyboard.
+fn key_code(name: &str) -> Option<KeyCode> {
+    const ROW_E: [KeyCode; 12] = [
+        KeyCode::KEY_1, KeyCode::KEY_2, KeyCode::KEY_3, KeyCode::KEY_4, KeyCode::KEY_5, KeyCode::KEY_6,
+        KeyCode::KEY_7, KeyCode::KEY_8, KeyCode::KEY_9, KeyCode::KEY_0, KeyCode::KEY_MINUS, KeyCode::KEY_EQUAL,
+    ];
+    const ROW_D: [KeyCode; 12] = [
+        KeyCode::KEY_Q, KeyCode::KEY_W, KeyCode::KEY_E, KeyCode::KEY_R, KeyCode::KEY_T, KeyCode::KEY_Y,
+        KeyCode::KEY_U, KeyCode::KEY_I, KeyCode::KEY_O, KeyCode::KEY_P, KeyCode::KEY_LEFTBRACE, KeyCode::KEY_RIGHTBRACE,
+    ];
+    const ROW_C: [KeyCode; 12] = [
+        KeyCode::KEY_A, KeyCode::KEY_S, KeyCode::KEY_D, KeyCode::KEY_F, KeyCode::KEY_G, KeyCode::KEY_H,
+        KeyCode::KEY_J, KeyCode::KEY_K, KeyCode::KEY_L, KeyCode::KEY_SEMICOLON, KeyCode::KEY_APOSTROPHE, KeyCode::KEY_BACKSLASH,
+    ];
+    const ROW_B: [KeyCode; 10] = [
+        KeyCode::KEY_Z, KeyCode::KEY_X, KeyCode::KEY_C, KeyCode::KEY_V, KeyCode::KEY_B,
+        KeyCode::KEY_N, KeyCode::KEY_M, KeyCode::KEY_COMMA, KeyCode::KEY_DOT, KeyCode::KEY_SLASH,
+    ];
+    match name {
+        "TLDE" => return Some(KeyCode::KEY_GRAVE),
+        "BKSL" => return Some(KeyCode::KEY_BACKSLASH),
+        "LSGT" => return Some(KeyCode::KEY_102ND),
+        "SPCE" => return Some(KeyCode::KEY_SPACE),
+        _ => {}
+    }
+    let row: &[KeyCode] = match name.get(..2)? {
+        "AE" => &ROW_E,
+        "AD" => &ROW_D,
+        "AC" => &ROW_C,
+        "AB" => &ROW_B,
+        _ => return None,
+    };
+    let column: usize = name.get(2..)?.parse().ok()?;
+    row.get(column.checked_sub(1)?).copied()
+}
+
+
+/// The character of an XKB keysym name, e.g. `a`, `exclam` or `U20AC`.
+fn keysym_char(symbol: &str) -> Option<char> {
+    let mut chars = symbol.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+    if let Some(hex) = symbol.strip_prefix('U') {
+        if let Some(c) = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+            return Some(c);
+        }
+    }
+    let c = match symbol {
+        "space" => ' ',
+        "exclam" => '!',
+        "quotedbl" => '"',
+        "numbersign" => '#',
+        "dollar" => '$',
+        "percent" => '%',
+        "ampersand" => '&',
+        "apostrophe" => '\'',
+        "parenleft" => '(',
+        "parenright" => ')',
+        "asterisk" => '*',
+        "plus" => '+',
+        "comma" => ',',
+        "minus" => '-',
+        "period" => '.',
+        "slash" => '/',
+        "colon" => ':',
+        "semicolon" => ';',
+        "less" => '<',
+        "equal" => '=',
+        "greater" => '>',
+        "question" => '?',
+        "at" => '@',
+        "bracketleft" => '[',
+        "backslash" => '\\',
+        "bracketright" => ']',
+        "asciicircum" => '^',
+        "underscore" => '_',
+        "grave" => '`',
+        "braceleft" => '{',
+        "bar" => '|',
+        "braceright" => '}',
+        "asciitilde" => '~',
+        "exclamdown" => '¡',
+        "cent" => '¢',
+        "sterling" => '£',
+        "currency" => '¤',
+        "yen" => '¥',
+        "section" => '§',
+        "copyright" => '©',
+        "ordfeminine" => 'ª',
+        "guillemotleft" => '«',
+        "notsign" => '¬',
+        "registered" => '®',
+        "macron" => '¯',
+        "degree" => '°',
+        "plusminus" => '±',
+        "twosuperior" => '²',
+        "threesuperior" => '³',
+        "mu" => 'µ',
+        "paragraph" => '¶',
+        "periodcentered" => '·',
+        "onesuperior" => '¹',
+        "masculine" => 'º',
+        "guillemotright" => '»',
+        "onequarter" => '¼',
+        "onehalf" => '½',
+        "questiondown" => '¿',
+        "multiply" => '×',
+        "division" => '÷',
+        "ssharp" => 'ß',
+        "adiaeresis" => 'ä',
+        "Adiaeresis" => 'Ä',
+        "odiaeresis" => 'ö',
+        "Odiaeresis" => 'Ö',
+        "udiaeresis" => 'ü',
+        "Udiaeresis" => 'Ü',
+        "ae" => 'æ',
+        "AE" => 'Æ',
+        "oslash" => 'ø',
+        "Oslash" => 'Ø',
+        "eth" => 'ð',
+        "ETH" => 'Ð',
+        "thorn" => 'þ',
+        "THORN" => 'Þ',
+        "EuroSign" => '€',
+        "endash" => '–',
+        "emdash" => '—',
+        "leftsinglequotemark" => '‘',
+        "rightsinglequotemark" => '’',
+        "singlelowquotemark" => '‚',
+        "leftdoublequotemark" => '“',
+        "rightdoublequotemark" => '”',
+        "doublelowquotemark" => '„',
+        "trademark" => '™',
+        _ => return None,
+    };
+    Some(c)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(events: Vec<InputEvent>) -> Vec<(KeyCode, i32)> {
+        events.iter().map(|ev| (KeyCode::new(ev.code()), ev.value())).collect()
+    }
+
+    fn tapped(events: Vec<InputEvent>) -> Vec<KeyCode> {
+        events.iter().filter(|ev| ev.value() == 1).map(|ev| KeyCode::new(ev.code())).collect()
+    }
+
+    #[test]
+    fn de_types_at_with_altgr_and_z_on_y() {
+        let de = Layout::load("de").unwrap();
+        assert_eq!(keys(de.events("@")), [
+            (KeyCode::KEY_RIGHTALT, 1),
+            (KeyCode::KEY_Q, 1),
+            (KeyCode::KEY_Q, 0),
+            (KeyCode::KEY_RIGHTALT, 0),
+        ]);
+        assert_eq!(keys(de.events("z")), [(KeyCode::KEY_Y, 1), (KeyCode::KEY_Y, 0)]);
+    }
+
+    #[test]
+    fn lowest_level_is_used() {
+        let layout = Layout::parse("
+            key <AE01> { [ 1, exclam, at, NoSymbol ] };
+            key <AE02> { [ 2, at, twosuperior, NoSymbol ] };
+            key <AD01> { [ q, Q, exclam, NoSymbol ] };
+        ").unwrap();
+        assert_eq!(layout.strokes[&'@'], Stroke { key: KeyCode::KEY_2, shift: true, altgr: false });
+        assert_eq!(layout.strokes[&'!'], Stroke { key: KeyCode::KEY_1, shift: true, altgr: false });
+    }
+
+    #[test]
+    fn dead_keys_are_skipped() {
+        let layout = Layout::parse("key <TLDE> { [ dead_circumflex, degree, U2032, U2033 ] };").unwrap();
+        assert!(!layout.strokes.contains_key(&'^'));
+        assert_eq!(layout.strokes[&'°'], Stroke { key: KeyCode::KEY_GRAVE, shift: true, altgr: false });
+        assert_eq!(layout.strokes[&'′'].level(), 3);
+    }
+
+    #[test]
+    fn unicode_fallback() {
+        let us = Layout::load("us").unwrap();
+        assert_eq!(tapped(us.events("€")), [
+            KeyCode::KEY_LEFTCTRL,
+            KeyCode::KEY_LEFTSHIFT,
+            KeyCode::KEY_U,
+            KeyCode::KEY_2,
+            KeyCode::KEY_0,
+            KeyCode::KEY_A,
+            KeyCode::KEY_C,
+            KeyCode::KEY_SPACE,
+        ]);
+    }
+
+    #[test]
+    fn text_without_keys() {
+        assert_eq!(Layout::parse("xkb_symbols \"basic\" {\n};").unwrap_err(), "no keys found");
+        assert!(Layout::parse("key <FK01> { [ F1 ] };").is_err());
+    }
+}
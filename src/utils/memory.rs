@@ -0,0 +1,214 @@
+use evdev::{
+    EventType,
+    InputEvent,
+    KeyCode,
+    SynchronizationCode,
+};
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{
+        sleep_until,
+        Duration,
+        Instant,
+    },
+};
+use crate::{
+    action::Runner,
+    error,
+    input::{
+        self,
+        Frame,
+        InputSink,
+        InputSource,
+    },
+    key_event_type::KeyEventType,
+    signals::{
+        Message,
+        VirtualDeviceTx,
+    },
+    stage::{
+        self,
+        Pipeline,
+    },
+};
+
+/// A scripted device for tests, each event comes after its delay.
+///
+/// Use with `#[tokio::test(start_paused = true)]` so the delays take no time.
+///
+/// ## Example
+///
+/// ```ignore
+/// let source = MemorySource::new()
+///     .press(KeyCode::KEY_CAPSLOCK)
+///     .wait(300)
+///     .release(KeyCode::KEY_CAPSLOCK);
+/// ```
+#[derive(Debug, Default)]
+pub struct MemorySource {
+    events: VecDeque<(Duration, InputEvent)>,
+    /// The delay of the next event added.
+    delay: Duration,
+    /// When the event being waited for is due, kept so a cancelled wait resumes.
+    due: Option<Instant>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait this many ms before the next event, or before the source ends.
+    pub fn wait(mut self, ms: u64) -> Self {
+        self.delay += Duration::from_millis(ms);
+        self
+    }
+
+    pub fn event(mut self, ev: InputEvent) -> Self {
+        self.events.push_back((mem::take(&mut self.delay), ev));
+        self
+    }
+
+    /// A key event, followed by its SYN_REPORT as a device sends it.
+    pub fn key(self, key: KeyCode, value: KeyEventType) -> Self {
+        self.event(InputEvent::new_now(EventType::KEY.0, key.code(), value.into())).sync()
+    }
+
+    /// End the frame of the events before.
+    pub fn sync(self) -> Self {
+        self.event(InputEvent::new_now(EventType::SYNCHRONIZATION.0, SynchronizationCode::SYN_REPORT.0, 0))
+    }
+
+    pub fn press(self, key: KeyCode) -> Self {
+        self.key(key, KeyEventType::PRESSED)
+    }
+
+    pub fn release(self, key: KeyCode) -> Self {
+        self.key(key, KeyEventType::RELEASED)
+    }
+
+    /// Press and release a key.
+    pub fn tap(self, key: KeyCode) -> Self {
+        self.press(key).release(key)
+    }
+}
+
+impl InputSource for MemorySource {
+    async fn next_event(&mut self) -> Option<InputEvent> {
+        let delay = self.events.front().map_or(self.delay, |(delay, _)| *delay);
+        let due = *self.due.get_or_insert_with(|| Instant::now() + delay);
+        sleep_until(due).await;
+        self.due = None;
+        match self.events.pop_front() {
+            Some((_, ev)) => Some(ev),
+            None => {
+                self.delay = Duration::ZERO;
+                None
+            }
+        }
+    }
+}
+
+
+/// Collects the events sent to it, with the time they were sent.
+#[derive(Clone, Debug)]
+pub struct MemorySink {
+    start: Instant,
+    events: Arc<Mutex<Vec<(Duration, InputEvent)>>>,
+}
+
+impl Default for MemorySink {
+    fn default() -> Self {
+        MemorySink { start: Instant::now(), events: Arc::default() }
+    }
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A sender like the one to the virtual device, whose events end up in the sink as sent.
+    pub fn channel() -> (VirtualDeviceTx, MemorySink) {
+        let sink = MemorySink::new();
+        let (tx, _) = sink.forward();
+        (tx, sink)
+    }
+
+    /// A sender whose events end up in this sink, and the task moving them, which ends once the sender is dropped.
+    pub fn forward(&self) -> (VirtualDeviceTx, JoinHandle<()>) {
+        let (tx, mut rx) = mpsc::channel::<Message>(32);
+        let mut forward = self.clone();
+        let forwarding = tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if let Message::Frame(_, frame) = message {
+                    let _ = forward.send(frame).await;
+                }
+            }
+        });
+        (VirtualDeviceTx::new("test", tx), forwarding)
+    }
+
+    pub fn events(&self) -> Vec<InputEvent> {
+        self.events.lock().unwrap().iter().map(|(_, ev)| *ev).collect()
+    }
+
+    /// The key events sent, as key and value.
+    pub fn keys(&self) -> Vec<(KeyCode, i32)> {
+        self.timed_keys().into_iter().map(|(_, key, value)| (key, value)).collect()
+    }
+
+    /// The key events sent, in the frames they were sent in.
+    pub fn key_frames(&self) -> Vec<Vec<(KeyCode, i32)>> {
+        let events = self.events();
+        events.split_inclusive(input::is_syn_report)
+            .map(|frame| frame.iter()
+                .filter(|ev| ev.event_type() == EventType::KEY)
+                .map(|ev| (KeyCode::new(ev.code()), ev.value()))
+                .collect())
+            .collect()
+    }
+
+    /// The key events sent, with the ms since the sink was created.
+    pub fn timed_keys(&self) -> Vec<(u64, KeyCode, i32)> {
+        self.events.lock().unwrap().iter()
+            .filter(|(_, ev)| ev.event_type() == EventType::KEY)
+            .map(|(at, ev)| (at.as_millis() as u64, KeyCode::new(ev.code()), ev.value()))
+            .collect()
+    }
+}
+
+impl InputSink for MemorySink {
+    async fn send(&mut self, frame: Frame) -> error::Result<()> {
+        let at = self.start.elapsed();
+        self.events.lock().unwrap().extend(frame.into_iter().map(|ev| (at, ev)));
+        Ok(())
+    }
+}
+
+
+/// Run a pipeline over the events of a source, returns what came out of it. Actions are only logged.
+pub async fn run(pipeline: Pipeline, source: MemorySource) -> MemorySink {
+    let sink = MemorySink::new();
+    stage::process(source, sink.clone(), Runner::Log, pipeline).await.unwrap();
+    sink
+}
+
+/// Like [`run`], but actions run and send their keys to the sink. Returns once they are done.
+pub async fn run_actions(pipeline: Pipeline, source: MemorySource) -> MemorySink {
+    let sink = MemorySink::new();
+    let (tx, forwarding) = sink.forward();
+    stage::process(source, sink.clone(), Runner::SendTo(tx), pipeline).await.unwrap();
+    // the actions still running hold the sender
+    forwarding.await.unwrap();
+    sink
+}
@@ -1,3 +1,18 @@
+pub mod action;
+pub mod chord;
+pub mod config;
+pub mod control;
+pub mod device_matcher;
+pub mod devices;
+pub mod error;
+pub mod evemu;
+pub mod input;
 pub mod key_event_type;
+pub mod layout;
+#[cfg(test)]
+pub mod memory;
+pub mod replay;
+pub mod shutdown;
 pub mod signals;
-pub mod functions;
\ No newline at end of file
+pub mod stage;
+pub mod functions;
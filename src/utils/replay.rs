@@ -0,0 +1,289 @@
+use evdev::{
+    EventType,
+    InputEvent,
+};
+use std::{
+    collections::VecDeque,
+    io,
+    path::Path,
+};
+use tokio::{
+    sync::mpsc,
+    time::{
+        sleep_until,
+        Duration,
+        Instant,
+    },
+};
+use crate::{
+    action,
+    config::Config,
+    evemu::{
+        self,
+        Description,
+        Trace,
+    },
+    input::InputSource,
+    signals::{
+        Message,
+        VirtualDeviceTx,
+    },
+    tasks::{
+        virtual_device::PressedKeys,
+        Task,
+        TaskConfig,
+        TaskEntry,
+    },
+};
+
+/// Plays the events of a trace at their times, as if read from the recorded device.
+pub struct Player {
+    device: Description,
+    events: VecDeque<(Duration, InputEvent)>,
+    /// When the source ends, after the last event.
+    end: Duration,
+    /// When the first event was asked for, the times of the events are from then.
+    start: Option<Instant>,
+}
+
+impl Player {
+    /// `linger` is how long the source goes on after the last event, so the timers of tasks can run out.
+    pub fn new(trace: &Trace, linger: Duration) -> Self {
+        let last = trace.events.last().map(|(at, _)| *at).unwrap_or_default();
+        Player {
+            device: trace.device.clone(),
+            events: trace.events.iter().copied().collect(),
+            end: last + linger,
+            start: None,
+        }
+    }
+
+    /// The recorded device.
+    pub fn device(&self) -> &Description {
+        &self.device
+    }
+}
+
+impl InputSource for Player {
+    async fn next_event(&mut self) -> Option<InputEvent> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let at = self.events.front().map_or(self.end, |(at, _)| *at);
+        sleep_until(start + at).await;
+        self.events.pop_front().map(|(_, ev)| ev)
+    }
+}
+
+
+/// Feed a trace through the tasks of the config which read the recorded device.
+///
+/// Each task gets the trace on its own, by default the tasks of the profile the
+/// config starts in. Actions are logged instead of run and macros are not saved,
+/// so any config can be replayed. What each task would send to the virtual device
+/// is printed in the evemu format, or compared to the events of `expect`.
+///
+/// ## Errors
+///
+/// Returns an `Err` if a file can not be read, no task reads the recorded device,
+/// or the events sent are not the expected ones.
+pub fn replay(config: &Config, path: &Path, task: Option<&str>, linger: Duration, expect: Option<&Path>) -> Result<(), String> {
+    let trace = Trace::load(path)?;
+    let reads = |entry: &&TaskEntry| entry.task.devices().iter().any(|matcher| matcher.matches(&trace.device));
+    let entries: Vec<&TaskEntry> = match task {
+        Some(name) => {
+            let entry = config.tasks.iter().find(|entry| entry.name() == name).ok_or_else(|| format!("no task named `{}`", name))?;
+            if !reads(&entry) {
+                return Err(format!("task `{}` does not read `{}`", name, trace.device.name));
+            }
+            vec![entry]
+        }
+        None => config.tasks.iter().filter(|entry| entry.in_profile(config.profile.as_deref())).filter(reads).collect(),
+    };
+    if entries.is_empty() {
+        return Err(format!("no task reads `{}`", trace.device.name));
+    }
+    if expect.is_some() && entries.len() > 1 {
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name()).collect();
+        return Err(format!("`{}` is read by tasks {}, give one with --task", trace.device.name, names.join(", ")));
+    }
+    let expected = expect.map(Trace::load).transpose()?;
+
+    action::set_dry_run(true);
+    for entry in entries {
+        info!("Replay {} through task {}, {} events", path.display(), entry.name(), trace.events.len());
+        let output = Trace {
+            device: Description::default(),
+            events: play(entry.task.clone(), Player::new(&trace, linger))?,
+        };
+        match &expected {
+            Some(expected) => compare(&output, expected)?,
+            None => {
+                println!("# task {}", entry.name());
+                output.write_events(&mut io::stdout()).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+
+/// Replay on a runtime of its own. With the `replay` feature its clock is paused, so the trace
+/// plays as fast as the task can take it, else it plays in real time. Returns the events the
+/// virtual device would send for the task, with their times from the first event.
+fn play(task: TaskConfig, player: Player) -> Result<Vec<(Duration, InputEvent)>, String> {
+    let mut builder = tokio::runtime::Builder::new_current_thread();
+    builder.enable_all();
+    // tokio's test-util, which tests get as a dev-dependency
+    #[cfg(any(test, feature = "replay"))]
+    builder.start_paused(true);
+    let runtime = builder.build().map_err(|e| e.to_string())?;
+    runtime.block_on(async move {
+        let start = Instant::now();
+        let (tx, mut rx) = mpsc::channel(32);
+        let replay = task.replay(player, VirtualDeviceTx::new("replay", tx));
+        tokio::pin!(replay);
+        let mut output = Vec::new();
+        let mut pressed = PressedKeys::default();
+        let mut receive = |message| {
+            let events = match message {
+                Message::Frame(source, frame) => pressed.filter_frame(source, frame).unwrap_or_default(),
+                Message::Ended(source, _) => pressed.release(source),
+            };
+            output.extend(events.into_iter().map(|ev| (start.elapsed(), ev)));
+        };
+        let result = loop {
+            tokio::select! {
+                biased;
+                Some(message) = rx.recv() => receive(message),
+                result = &mut replay => break result,
+            }
+        };
+        // repeats of auto_repeat tasks would go on, what they sent so far is kept
+        while let Ok(message) = rx.try_recv() {
+            receive(message);
+        }
+        result.map(|_| output).map_err(|e| e.to_string())
+    })
+}
+
+
+/// Compare the type, code and value of the events.
+///
+/// Times and frames are not compared, so the output of the virtual device recorded
+/// with `macrokey record` can be the expected trace.
+fn compare(output: &Trace, expected: &Trace) -> Result<(), String> {
+    let events = |trace: &Trace| -> Vec<(Duration, InputEvent)> {
+        trace.events.iter().filter(|(_, ev)| ev.event_type() != EventType::SYNCHRONIZATION).copied().collect()
+    };
+    let (sent, wanted) = (events(output), events(expected));
+    let same = |a: Option<&(Duration, InputEvent)>, b: Option<&(Duration, InputEvent)>| {
+        a.map(|(_, ev)| (ev.event_type(), ev.code(), ev.value())) == b.map(|(_, ev)| (ev.event_type(), ev.code(), ev.value()))
+    };
+    let Some(i) = (0..sent.len().max(wanted.len())).find(|&i| !same(sent.get(i), wanted.get(i))) else {
+        info!("Output as expected, {} events", sent.len());
+        return Ok(());
+    };
+    let line = |events: &[(Duration, InputEvent)]| match events.get(i) {
+        Some((at, ev)) => evemu::event_line(*at, ev),
+        None => "nothing".to_string(),
+    };
+    Err(format!("event {} is not as expected, {} events sent and {} expected\nexpected: {}\nsent:     {}",
+        i + 1, sent.len(), wanted.len(), line(&wanted), line(&sent)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use evdev::KeyCode;
+    use super::*;
+
+    const TRACE: &str = "\
+N: AT Translated Set 2 keyboard
+I: 0011 0001 0001 ab83
+B: 00 03
+E: 0.000000 0001 003a 0001
+E: 0.000000 0000 0000 0000
+E: 0.300000 0001 003a 0000
+E: 0.300000 0000 0000 0000
+";
+
+    fn task(toml: &str) -> TaskConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn keys(events: &[(Duration, InputEvent)]) -> Vec<(u128, KeyCode, i32)> {
+        events.iter()
+            .filter(|(_, ev)| ev.event_type() == EventType::KEY)
+            .map(|(at, ev)| (at.as_millis(), KeyCode::new(ev.code()), ev.value()))
+            .collect()
+    }
+
+    #[test]
+    fn trace_plays_at_its_times() {
+        let remap = task(r#"
+            type = "remap"
+            [[device]]
+            device = "keyboard"
+            tap_hold = { CAPSLOCK = { tap = "esc", hold = "leftctrl" } }
+        "#);
+        let trace: Trace = TRACE.parse().unwrap();
+        let output = play(remap, Player::new(&trace, Duration::from_secs(1))).unwrap();
+        assert_eq!(keys(&output), [
+            (200, KeyCode::KEY_LEFTCTRL, 1),
+            (300, KeyCode::KEY_LEFTCTRL, 0),
+        ]);
+    }
+
+    #[test]
+    fn repeats_run_until_the_linger_ends() {
+        let auto_repeat = task(r#"
+            type = "auto_repeat"
+            hold = 50
+            interval = 150
+        "#);
+        let trace: Trace = "\
+E: 0.0 0001 001d 0001
+E: 0.0 0001 0038 0001
+E: 0.0 0001 003f 0001
+".parse().unwrap();
+        let output = play(auto_repeat, Player::new(&trace, Duration::from_millis(300))).unwrap();
+        assert_eq!(keys(&output), [
+            (0, KeyCode::KEY_F5, 1),
+            (50, KeyCode::KEY_F5, 0),
+            (200, KeyCode::KEY_F5, 1),
+            (250, KeyCode::KEY_F5, 0),
+        ]);
+    }
+
+    #[test]
+    fn keys_left_pressed_are_released() {
+        let remap = task(r#"
+            type = "remap"
+            [[device]]
+            device = "keyboard"
+            map = { A = "b" }
+        "#);
+        // A is pressed twice and never released
+        let trace: Trace = "\
+N: AT Translated Set 2 keyboard
+B: 00 03
+E: 0.000000 0001 001e 0001
+E: 0.000000 0000 0000 0000
+E: 0.100000 0001 001e 0001
+E: 0.100000 0000 0000 0000
+".parse().unwrap();
+        let output = play(remap, Player::new(&trace, Duration::from_millis(100))).unwrap();
+        assert_eq!(keys(&output), [
+            (0, KeyCode::KEY_B, 1),
+            (200, KeyCode::KEY_B, 0),
+        ]);
+    }
+
+    #[test]
+    fn compare_skips_times_and_frames() {
+        let sent: Trace = "E: 0.0 0001 0001 0001\nE: 0.0 0001 0001 0000\n".parse().unwrap();
+        let recorded: Trace = "E: 5.0 0001 0001 0001\nE: 5.0 0000 0000 0000\nE: 5.1 0001 0001 0000\n".parse().unwrap();
+        assert_eq!(compare(&sent, &recorded), Ok(()));
+        let missing: Trace = "E: 0.0 0001 0001 0001\n".parse().unwrap();
+        assert!(compare(&missing, &recorded).unwrap_err().starts_with("event 2 is not as expected"));
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    fmt,
+    future::pending,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Mutex,
+    },
+};
+use tokio::{
+    signal::unix::{
+        signal,
+        SignalKind,
+    },
+    sync::Notify,
+    task::JoinHandle,
+    time::{
+        timeout,
+        Duration,
+    },
+};
+use crate::{
+    control,
+    error,
+    tasks::{
+        self,
+        auto_repeat,
+        virtual_device,
+    },
+};
+
+/// How long the tasks, and then the virtual device, get to stop before macrokey exits anyway.
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Set once the shutdown started, tasks are not started again after it.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Returns true once the shutdown started.
+pub fn is_stopping() -> bool {
+    STOPPING.load(Ordering::Relaxed)
+}
+
+/// The first failure given to [`fail`], and [`coordinate`] woken for it.
+static FAILURE: Mutex<Option<String>> = Mutex::new(None);
+static FAILED: Notify = Notify::const_new();
+
+/// Shut down with [`Reason::Failed`], for failures macrokey can not go on after,
+/// such as a task which can not be restarted.
+///
+/// The shutdown is left to [`coordinate`], it stops the tasks so it can not run in one of them.
+pub fn fail(error: String) {
+    FAILURE.lock().unwrap().get_or_insert(error);
+    FAILED.notify_one();
+}
+
+
+/// Why macrokey shuts down.
+#[derive(Debug)]
+pub enum Reason {
+    /// SIGINT or SIGTERM, e.g. Ctrl-C or `systemctl stop`.
+    Signal(&'static str),
+    /// Something macrokey can not run without stopped.
+    Failed(String),
+}
+
+impl Reason {
+    /// 0 when asked to stop, so systemd sees a clean stop, 1 on a failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Reason::Signal(_) => 0,
+            Reason::Failed(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reason::Signal(signal) => write!(f, "got {}", signal),
+            Reason::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+
+/// Waits for SIGINT or SIGTERM, forever if they can not be listened for.
+pub async fn signal_received() -> Reason {
+    let (mut interrupt, mut terminate) = match (signal(SignalKind::interrupt()), signal(SignalKind::terminate())) {
+        (Ok(interrupt), Ok(terminate)) => (interrupt, terminate),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Can not listen for signals: {}", e);
+            return pending().await;
+        }
+    };
+    tokio::select! {
+        _ = interrupt.recv() => Reason::Signal("SIGINT"),
+        _ = terminate.recv() => Reason::Signal("SIGTERM"),
+    }
+}
+
+
+/// Waits for a signal, a [`fail`]ure or the virtual device to stop, then shuts down and exits.
+pub async fn coordinate(mut device: JoinHandle<error::Result<()>>) -> ! {
+    let ended = tokio::select! {
+        reason = signal_received() => Err(reason),
+        _ = FAILED.notified() => Err(Reason::Failed(FAILURE.lock().unwrap().take().unwrap_or_default())),
+        ended = &mut device => Ok(ended),
+    };
+    let (reason, device) = match ended {
+        Err(reason) => (reason, Some(device)),
+        Ok(Ok(Ok(()))) => (Reason::Failed("the virtual device stopped".to_string()), None),
+        Ok(Ok(Err(e))) => (Reason::Failed(e.to_string()), None),
+        Ok(Err(e)) => (Reason::Failed(format!("the virtual device {}", e)), None),
+    };
+    shutdown(reason, device).await
+}
+
+
+/// Leave the desktop as it was before macrokey started, then exit.
+///
+/// The repeats are stopped, then the tasks are stopped and waited for, their
+/// devices are closed and so ungrabbed as the runtime drops the aborted reads,
+/// or when the process exits. Last the virtual device sends what the
+/// tasks sent before they stopped, and releases every key it still has pressed.
+/// The control socket is removed.
+pub async fn shutdown(reason: Reason, device: Option<JoinHandle<error::Result<()>>>) -> ! {
+    info!("\n== Shutdown: {} ==", reason);
+    STOPPING.store(true, Ordering::Relaxed);
+
+    // errors if no auto repeat task is running
+    let _ = auto_repeat::control(None, auto_repeat::Control::StopAll).await;
+    if timeout(STOP_TIMEOUT, tasks::stop_all()).await.is_err() {
+        error!("Tasks did not stop within {}s", STOP_TIMEOUT.as_secs());
+    }
+
+    if let Some(device) = device {
+        virtual_device::finish();
+        match timeout(STOP_TIMEOUT, device).await {
+            Ok(Ok(Ok(()))) => {}
+            Ok(Ok(Err(e))) => error!("{}", e),
+            Ok(Err(e)) => error!("The virtual device {}", e),
+            Err(_) => error!("The virtual device did not stop within {}s", STOP_TIMEOUT.as_secs()),
+        }
+    }
+
+    control::remove_socket();
+    info!("== Stop MacroKey ==");
+    std::process::exit(reason.exit_code())
+}
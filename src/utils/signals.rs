@@ -1,37 +1,140 @@
-use tokio::sync::{
-    mpsc,
-    Mutex
+use tokio::{
+    runtime::Handle,
+    sync::{
+        mpsc::{
+            self,
+            error::TrySendError,
+        },
+        Mutex
+    },
 };
-use evdev::InputEvent;
 use once_cell::sync::Lazy;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    Arc,
+};
+use crate::{
+    error,
+    input::{
+        Frame,
+        InputSink,
+    },
+};
 
-pub static VIRTUAL_DEVICE_CHANNEL: Lazy<(Arc<Mutex<mpsc::Sender<InputEvent>>>, Arc<Mutex<mpsc::Receiver<InputEvent>>>)> = Lazy::new(|| {
-    let (tx, rx) = mpsc::channel::<InputEvent>(32);
+type Channel = (Arc<Mutex<mpsc::Sender<Message>>>, Arc<Mutex<mpsc::Receiver<Message>>>);
+
+pub static VIRTUAL_DEVICE_CHANNEL: Lazy<Channel> = Lazy::new(|| {
+    let (tx, rx) = mpsc::channel::<Message>(32);
     (Arc::new(Mutex::new(tx)), Arc::new(Mutex::new(rx)))
 });
 
-/// Asynchronously retrieves a clone of the `Sender` for the virtual device channel.
+/// The ids of the sources, counting up.
+static NEXT_SOURCE: AtomicU64 = AtomicU64::new(0);
+
+
+/// What is sent to the virtual device.
+#[derive(Debug)]
+pub enum Message {
+    /// A frame and where it came from.
+    Frame(SourceId, Frame),
+    /// The source is gone, e.g. its device was unplugged or its task stopped, with its name.
+    Ended(SourceId, String),
+}
+
+
+/// Where events sent to the virtual device came from, a device of a task or an action.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceId(u64);
+
+impl SourceId {
+    /// A source which was not used before.
+    pub fn new() -> Self {
+        SourceId(NEXT_SOURCE.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Default for SourceId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Sends the events of one source to the virtual device.
+///
+/// Clones are the same source. Once the last clone is dropped the virtual device
+/// is told the source ended, so it can release the keys the source left pressed.
+#[derive(Clone, Debug)]
+pub struct VirtualDeviceTx {
+    source: Arc<Source>,
+}
+
+#[derive(Debug)]
+struct Source {
+    id: SourceId,
+    name: String,
+    tx: mpsc::Sender<Message>,
+}
+
+impl VirtualDeviceTx {
+    /// A sender for a new source, named for the logs, on a channel of its own such as in tests.
+    pub fn new(name: &str, tx: mpsc::Sender<Message>) -> Self {
+        VirtualDeviceTx { source: Arc::new(Source { id: SourceId::new(), name: name.to_string(), tx }) }
+    }
+
+    /// Send a frame to the virtual device, see [`input::frame`](crate::input::frame).
+    ///
+    /// ## Errors
+    ///
+    /// Returns an `Err` if the virtual device task stopped.
+    pub async fn send(&self, frame: Frame) -> error::Result<()> {
+        Ok(self.source.tx.send(Message::Frame(self.source.id, frame)).await?)
+    }
+}
+
+impl InputSink for VirtualDeviceTx {
+    async fn send(&mut self, frame: Frame) -> error::Result<()> {
+        VirtualDeviceTx::send(self, frame).await
+    }
+}
+
+impl Drop for Source {
+    fn drop(&mut self) {
+        let ended = Message::Ended(self.id, std::mem::take(&mut self.name));
+        // after the events of the source already on the channel
+        if let Err(TrySendError::Full(ended)) = self.tx.try_send(ended) {
+            if let Ok(runtime) = Handle::try_current() {
+                let tx = self.tx.clone();
+                runtime.spawn(async move { let _ = tx.send(ended).await; });
+            }
+        }
+    }
+}
+
+
+/// Asynchronously retrieves a sender to the virtual device for a new source.
 ///
 /// This function acquires a lock on the transmitter part of the global virtual device channel
-/// and returns a clone of it. The channel is used for sending `InputEvent` messages
+/// and returns a sender with a clone of it. The channel is used for sending frames of `InputEvent`s
 /// to the virtual device. The clone will not be locked, so it can be used to send messages
-/// concurrently.
+/// concurrently. `source` names where the events come from in the logs, such as the task.
 ///
 /// ## Returns
 ///
-/// A `Sender<InputEvent>` which can be used to send key events to the virtual device.
-
-pub async fn get_virtual_device_tx() -> mpsc::Sender<InputEvent> {
+/// A `VirtualDeviceTx` which can be used to send key events to the virtual device.
+pub async fn get_virtual_device_tx(source: &str) -> VirtualDeviceTx {
     let tx = VIRTUAL_DEVICE_CHANNEL.0.lock().await;
-    tx.clone()
+    VirtualDeviceTx::new(source, tx.clone())
 }
 
 /// Asynchronously retrieves a lock on the `Receiver` for the virtual device channel.
 ///
 /// This function acquires a lock on the receiver part of the global virtual device channel
-/// and returns a guard over it. The channel is used for receiving `InputEvent` messages
-/// from the virtual device. The guard will be locked until it is dropped.
-pub async fn get_virtual_device_rx() -> tokio::sync::MutexGuard<'static, mpsc::Receiver<InputEvent>> {
+/// and returns a guard over it. The channel is used for receiving `Message`s for the
+/// virtual device. The guard will be locked until it is dropped.
+pub async fn get_virtual_device_rx() -> tokio::sync::MutexGuard<'static, mpsc::Receiver<Message>> {
     VIRTUAL_DEVICE_CHANNEL.1.lock().await
 }
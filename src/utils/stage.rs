@@ -0,0 +1,219 @@
+use evdev::{
+    Device,
+    InputEvent,
+};
+use tokio::{
+    time::{
+        sleep_until,
+        Instant,
+    },
+};
+use crate::{
+    action::{
+        Action,
+        Runner,
+    },
+    error::{
+        self,
+        Error,
+    },
+    functions,
+    input::{
+        self,
+        Discard,
+        Frame,
+        InputSink,
+        InputSource,
+    },
+    signals::{
+        self,
+        VirtualDeviceTx,
+    },
+};
+
+/// A step events of a device go through on their way to the virtual device.
+///
+/// A stage passes, drops or changes each event by what it returns, and can add
+/// events of its own. Stages which wait, such as for the rest of a sequence,
+/// give a deadline and are asked for their events when it passes. Actions a stage
+/// matched are run once the events it returned before them are sent, so the
+/// keys of an action never come before modifiers the stage released for it.
+pub trait Stage: Send {
+    /// The events to pass to the next stage for an event.
+    fn process(&mut self, ev: InputEvent) -> Vec<InputEvent>;
+
+    /// When the stage wants [`Stage::timeout`] called, if it is waiting.
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// The deadline passed, returns the events to pass to the next stage.
+    fn timeout(&mut self) -> Vec<InputEvent> {
+        Vec::new()
+    }
+
+    /// The actions matched since it was last asked.
+    fn actions(&mut self) -> Vec<Action> {
+        Vec::new()
+    }
+}
+
+
+/// Stages in order, the events of each stage are processed by the next.
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Box<dyn Stage>>) -> Self {
+        Pipeline { stages }
+    }
+
+    /// The events that come out of the last stage for an event of the device.
+    pub fn process(&mut self, ev: InputEvent) -> Vec<InputEvent> {
+        self.feed(0, vec![ev])
+    }
+
+    /// The earliest deadline of the stages.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.stages.iter().filter_map(|stage| stage.deadline()).min()
+    }
+
+    /// Time out the stages whose deadline passed, their events go through the stages after them.
+    pub fn timeout(&mut self) -> Vec<InputEvent> {
+        let now = Instant::now();
+        let mut output = Vec::new();
+        for i in 0..self.stages.len() {
+            if self.stages[i].deadline().is_some_and(|deadline| deadline <= now) {
+                let events = self.stages[i].timeout();
+                output.extend(self.feed(i + 1, events));
+            }
+        }
+        output
+    }
+
+    /// The actions the stages matched, in the order of the stages.
+    pub fn actions(&mut self) -> Vec<Action> {
+        self.stages.iter_mut().flat_map(|stage| stage.actions()).collect()
+    }
+
+    /// Pass events through the stages from `first` on.
+    fn feed(&mut self, first: usize, mut events: Vec<InputEvent>) -> Vec<InputEvent> {
+        for stage in self.stages[first..].iter_mut() {
+            if events.is_empty() { break }
+            events = events.into_iter().flat_map(|ev| stage.process(ev)).collect();
+        }
+        events
+    }
+}
+
+
+/// Read the events of a device through the pipeline, until it is unplugged.
+///
+/// A grabbed device is only seen by applications through the virtual device, so
+/// the events out of the pipeline are sent there. Without a grab applications
+/// already have the events, the stages only watch them.
+///
+/// ## Errors
+///
+/// Returns an `Err` if the device can not be grabbed or read, e.g. because another
+/// program grabbed it, or the virtual device stopped.
+pub async fn run(mut device: Device, grab: bool, pipeline: Pipeline) -> error::Result<()> {
+    functions::log_device_keys(&device);
+    if grab {
+        device.grab().map_err(|e| Error::device(&device, e))?;
+    }
+    let name = device.name().unwrap_or("<unnamed>").to_string();
+    // the keys the device holds down are released when it is unplugged
+    let tx = signals::get_virtual_device_tx(&format!("device {}", name)).await;
+    let events = device.into_event_stream().map_err(|e| Error::Device { name, source: e })?;
+    forward(events, tx, grab, pipeline).await
+}
+
+
+/// [`process`] the events of a source, sending what comes out to `tx` if the device is grabbed.
+pub async fn forward(source: impl InputSource, tx: VirtualDeviceTx, grab: bool, pipeline: Pipeline) -> error::Result<()> {
+    match grab {
+        true => process(source, tx, Runner::Spawn, pipeline).await,
+        false => process(source, Discard, Runner::Spawn, pipeline).await,
+    }
+}
+
+
+/// Pass the events of a source through the pipeline to a sink, until the source ends.
+///
+/// What comes out of the pipeline for a frame of the source is sent as one frame
+/// when the frame ends, so a mouse motion or keys rolled over stay together. Events
+/// stages make up, such as a tap or the keys held back for a sequence, are split into
+/// frames by [`input::frames`], and sent at once. The actions the stages matched
+/// are run by `runner` once what came out before them is sent.
+///
+/// ## Errors
+///
+/// Returns an `Err` if the sink fails, e.g. the virtual device stopped.
+pub async fn process(mut source: impl InputSource, mut sink: impl InputSink, runner: Runner, mut pipeline: Pipeline) -> error::Result<()> {
+    // what came out of the pipeline for the frame of the source so far
+    let mut frame = Vec::new();
+    let mut actions = Vec::new();
+    loop {
+        let deadline = pipeline.deadline();
+        tokio::select! {
+            ev = source.next_event() => match ev {
+                Some(ev) => {
+                    add(&mut sink, &mut frame, pipeline.process(ev)).await?;
+                    actions.extend(pipeline.actions());
+                    if input::is_syn_report(&ev) {
+                        end(&mut sink, &mut frame).await?;
+                    }
+                }
+                None => break,
+            },
+            // a stage waited long enough, e.g. for the next chord of a sequence
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let events = pipeline.timeout();
+                actions.extend(pipeline.actions());
+                end(&mut sink, &mut frame).await?;
+                for made in made_up(&events) {
+                    sink.send(made).await?;
+                }
+            }
+        }
+        if frame.is_empty() {
+            actions.drain(..).for_each(|action| runner.run(action));
+        }
+    }
+    // the source ended in the middle of a frame
+    end(&mut sink, &mut frame).await?;
+    actions.into_iter().for_each(|action| runner.run(action));
+    Ok(())
+}
+
+
+/// Add what the pipeline made of an event of the source to the frame.
+///
+/// Events which are more than one frame were made up by a stage, the frame so far is sent before them.
+async fn add(sink: &mut impl InputSink, frame: &mut Vec<InputEvent>, events: Vec<InputEvent>) -> error::Result<()> {
+    let made = made_up(&events);
+    if made.len() <= 1 {
+        frame.extend(events.into_iter().filter(|ev| !input::is_syn_report(ev)));
+        return Ok(());
+    }
+    end(sink, frame).await?;
+    for made in made {
+        sink.send(made).await?;
+    }
+    Ok(())
+}
+
+
+/// Send the frame of the source, unless the stages dropped all of it.
+async fn end(sink: &mut impl InputSink, frame: &mut Vec<InputEvent>) -> error::Result<()> {
+    if frame.is_empty() { return Ok(()) }
+    sink.send(input::frame(std::mem::take(frame))).await
+}
+
+
+/// The frames of events made up by stages, the frames of events they held back stay apart.
+fn made_up(events: &[InputEvent]) -> Vec<Frame> {
+    events.split(input::is_syn_report).flat_map(|events| input::frames(events.iter().copied())).collect()
+}